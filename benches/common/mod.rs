@@ -0,0 +1,18 @@
+// Copyright (C) 2026 Mike Kuyper <mike@kuyper.us>. All rights reserved.
+//
+// This file is subject to the terms and conditions defined in file 'LICENSE',
+// which is part of this source code package.
+
+//! Timing shared by the benchmarks.
+
+/// Runs `f` a few times and prints the fastest run, which is returned.
+pub fn bench<T>(name: &str, mut f: impl FnMut() -> T) -> std::time::Duration {
+    let mut best = std::time::Duration::MAX;
+    for _ in 0..5 {
+        let start = std::time::Instant::now();
+        std::hint::black_box(f());
+        best = best.min(start.elapsed());
+    }
+    println!("{:<48} {:>9.2} ms", name, best.as_secs_f64() * 1e3);
+    best
+}
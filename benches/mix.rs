@@ -0,0 +1,101 @@
+// Copyright (C) 2026 Mike Kuyper <mike@kuyper.us>. All rights reserved.
+//
+// This file is subject to the terms and conditions defined in file 'LICENSE',
+// which is part of this source code package.
+
+//! Benchmarks of the mixer, run with `cargo bench --bench mix`.
+
+mod common;
+
+use common::bench;
+use midisynth_rs::synth::*;
+
+const SR: usize = 44100;
+
+/// Tracks of noise, as rendered tracks are the same to the mixer, spread over the stereo field
+/// and slightly offset from each other.
+fn tracks(count: usize, secs: usize) -> Vec<MixerTrack> {
+    let mut state = 0x2545_f491_u32;
+    let mut noise = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as f32 / u32::MAX as f32 - 0.5
+    };
+    (0..count)
+        .map(|i| MixerTrack {
+            name: format!("Track {}", i),
+            track: None,
+            left: (0..secs * SR).map(|_| noise()).collect(),
+            right: (0..secs * SR).map(|_| noise()).collect(),
+            gainfactors: MixerGainFactors::new(-6.0, i as f32 / count as f32 * 2.0 - 1.0),
+            offset: i as isize * 100,
+            output: 0,
+            bus: None,
+        })
+        .collect()
+}
+
+fn main() {
+    // Progress of a multi-minute mix, which was once reported for every sample
+    let mixer = Mixer {
+        tracks: tracks(10, 300),
+        precision: MixPrecision::Single,
+        channels: 2,
+    };
+    let updates = std::sync::atomic::AtomicU64::new(0);
+    let counting = |_: midisynth_rs::Stage, _: u64, _: u64| {
+        updates.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    };
+    let quiet = bench("10 tracks of 5 minutes, without progress", || {
+        mixer.mix(&())
+    });
+    let reported = bench("10 tracks of 5 minutes, with progress", || {
+        mixer.mix(&counting)
+    });
+    println!(
+        "{:<48} {:>9.2} x, {} updates per mix",
+        "  progress overhead",
+        reported.as_secs_f64() / quiet.as_secs_f64(),
+        updates.into_inner() / 5
+    );
+
+    // Throughput of the summing loop, into stereo and into four channel pairs
+    let samples = mixer.tracks.iter().map(|t| t.left.len()).sum::<usize>() as f64;
+    let stereo = quiet;
+    let mut outputs = mixer.tracks;
+    for (i, t) in outputs.iter_mut().enumerate() {
+        t.output = i % 4;
+    }
+    let mixer = Mixer {
+        tracks: outputs,
+        precision: MixPrecision::Single,
+        channels: 8,
+    };
+    let pairs = bench("10 tracks of 5 minutes, into 4 channel pairs", || {
+        mixer.mix(&())
+    });
+    for (name, time) in [("  stereo", stereo), ("  4 channel pairs", pairs)] {
+        let rate = samples / time.as_secs_f64() / 1e6;
+        println!("{:<48} {:>9.2} M track frames/s", name, rate);
+    }
+
+    // Cost of summing in double precision, which mixes of more than 32 tracks do by default
+    let mut mixer = Mixer {
+        tracks: tracks(64, 60),
+        precision: MixPrecision::Single,
+        channels: 2,
+    };
+    let single = bench("64 tracks of 1 minute, in single precision", || {
+        mixer.mix(&())
+    });
+    mixer.precision = MixPrecision::Double;
+    let double = bench("64 tracks of 1 minute, in double precision", || {
+        mixer.mix(&())
+    });
+    println!(
+        "{:<48} {:>9.2} x",
+        "  double precision overhead",
+        double.as_secs_f64() / single.as_secs_f64()
+    );
+}
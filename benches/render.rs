@@ -0,0 +1,82 @@
+// Copyright (C) 2026 Mike Kuyper <mike@kuyper.us>. All rights reserved.
+//
+// This file is subject to the terms and conditions defined in file 'LICENSE',
+// which is part of this source code package.
+
+//! Benchmarks of rendering, run with `cargo bench --bench render`.
+
+mod common;
+
+use common::bench;
+
+/// A single-track file at 120 BPM of the given number of beats, playing an eighth note every
+/// `every` beats.
+fn melody(beats: u32, every: u32) -> Vec<u8> {
+    let midi = |message| midly::TrackEventKind::Midi {
+        channel: 0.into(),
+        message,
+    };
+    let mut events = vec![(
+        0,
+        midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(b"Piano")),
+    )];
+    for i in 0..beats / every {
+        let key = (60 + i % 12) as u8;
+        let rest = if i == 0 { 0 } else { every * 480 - 240 };
+        events.extend([
+            (
+                rest,
+                midi(midly::MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: 100.into(),
+                }),
+            ),
+            (
+                240,
+                midi(midly::MidiMessage::NoteOff {
+                    key: key.into(),
+                    vel: 64.into(),
+                }),
+            ),
+        ]);
+    }
+    events.push((
+        every * 480 - 240,
+        midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+    ));
+    let track = events
+        .into_iter()
+        .map(|(delta, kind)| midly::TrackEvent {
+            delta: delta.into(),
+            kind,
+        })
+        .collect();
+    let mut data = Vec::new();
+    midly::Smf {
+        header: midly::Header::new(
+            midly::Format::SingleTrack,
+            midly::Timing::Metrical(480.into()),
+        ),
+        tracks: vec![track],
+    }
+    .write_std(&mut data)
+    .unwrap();
+    data
+}
+
+fn main() {
+    // Ten minutes of music, with a note every beat or one every 20 seconds; the long rests of
+    // the sparse melody are skipped once the reverb has died down
+    let soundfont = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/sine.sf2"));
+    let soundfont = soundfont.unwrap();
+    let config = "[[instr.Piano]]\npreset = 0\n";
+    let render = |midi: &[u8]| midisynth_rs::render_midi(midi, &soundfont, config).unwrap();
+    let (dense, sparse) = (melody(1200, 1), melody(1200, 40));
+    let dense = bench("10 minutes, a note every beat", || render(&dense));
+    let sparse = bench("10 minutes, a note every 20 s", || render(&sparse));
+    println!(
+        "{:<48} {:>9.2} x",
+        "  sparse speedup",
+        dense.as_secs_f64() / sparse.as_secs_f64()
+    );
+}
@@ -0,0 +1,259 @@
+// Copyright (C) 2026 Mike Kuyper <mike@kuyper.us>. All rights reserved.
+//
+// This file is subject to the terms and conditions defined in file 'LICENSE',
+// which is part of this source code package.
+
+//! Synthesizes MIDI files using soundfonts, rendering each track separately according to a
+//! configured instrument mapping and mixing the rendered tracks into stereo audio.
+//!
+//! Besides the `midisynth-rs` command line tool, the rendering pipeline can be used through
+//! [`render_midi`].
+
+#[doc(hidden)]
+pub mod synth;
+
+use synth::*;
+
+/// A stage of the rendering pipeline, for progress reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Sequencing the MIDI file, counted in events.
+    Sequencing,
+    /// Rendering the tracks, counted in samples.
+    Rendering,
+    /// Mixing the tracks, counted in samples.
+    Mixing,
+}
+
+/// Receives the progress of the rendering pipeline: the units of work of a stage done so far,
+/// out of its total. Closures taking the same arguments implement it, `()` ignores progress.
+pub trait Progress {
+    fn update(&self, stage: Stage, current: u64, total: u64);
+}
+
+impl<F: Fn(Stage, u64, u64)> Progress for F {
+    fn update(&self, stage: Stage, current: u64, total: u64) {
+        self(stage, current, total)
+    }
+}
+
+impl Progress for () {
+    fn update(&self, _stage: Stage, _current: u64, _total: u64) {}
+}
+
+/// Sample rate of the audio returned by [`render_midi`] (in Hz).
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Errors returned by [`render_midi`].
+#[derive(Debug)]
+pub enum MidiSynthError {
+    /// The MIDI file can't be parsed or uses timing that isn't supported.
+    Midi(String),
+    /// The soundfont can't be loaded.
+    Soundfont(String),
+    /// The configuration is invalid.
+    Config(String),
+    /// A track failed to render, or no track could be rendered.
+    Render(String),
+}
+
+impl std::fmt::Display for MidiSynthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Midi(msg) => write!(f, "Loading MIDI file failed: {}", msg),
+            Self::Soundfont(msg) => write!(f, "Loading soundfont failed: {}", msg),
+            Self::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            Self::Render(msg) => write!(f, "Rendering failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for MidiSynthError {}
+
+/// Renders a MIDI file with a soundfont, both given as file contents, and returns the mix as
+/// interleaved stereo samples at [`SAMPLE_RATE`].
+///
+/// The configuration is given in the TOML format of the configuration files of the command line
+/// tool, with the `soundfont` key being ignored. The instrument, tempo, bus and reverb settings
+/// apply, while the options that only the command line offers (such as a duration limit or
+/// loudness normalization) don't. A microtonal scale is an error, as `tuning_file` and
+/// `keyboard_map` name files, which aren't read. If any track fails to render, so does the whole
+/// mix.
+///
+/// # Example
+///
+/// ```
+/// // A single-track file playing middle C for one beat on a track named "Piano"
+/// let midi = [
+///     b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xe0".as_slice(),
+///     b"MTrk\x00\x00\x00\x16",
+///     b"\x00\xff\x03\x05Piano",
+///     b"\x00\x90\x3c\x64\x83\x60\x80\x3c\x40",
+///     b"\x00\xff\x2f\x00",
+/// ]
+/// .concat();
+/// // The sine wave soundfont of the tests of this crate
+/// let soundfont = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/sine.sf2"))?;
+/// let config = r#"
+///     [[instr.Piano]]
+///     preset = 0
+/// "#;
+///
+/// let samples = midisynth_rs::render_midi(&midi, &soundfont, config)?;
+/// // Half a second of music at 120 BPM, followed by the release of the note
+/// assert!(samples.len() / 2 > midisynth_rs::SAMPLE_RATE as usize / 2);
+/// assert!(samples.iter().any(|s| s.abs() > 0.01));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn render_midi(
+    midi: &[u8],
+    soundfont: &[u8],
+    config: &str,
+) -> Result<Vec<f32>, MidiSynthError> {
+    render_midi_with_progress(midi, soundfont, config, &())
+}
+
+/// Renders a MIDI file like [`render_midi`], reporting the progress of each stage. The tracks
+/// are rendered in parallel, so progress may be reported from several threads; the rendering
+/// progress is the total of all tracks.
+pub fn render_midi_with_progress(
+    midi: &[u8],
+    soundfont: &[u8],
+    config: &str,
+    progress: &(dyn Progress + Sync),
+) -> Result<Vec<f32>, MidiSynthError> {
+    let invalid = MidiSynthError::Config;
+    let config = config
+        .parse::<toml::Table>()
+        .map_err(|e| invalid(e.to_string()))?;
+    let sf_object = std::sync::Arc::new(
+        rustysynth::SoundFont::new(&mut &soundfont[..])
+            .map_err(|e| MidiSynthError::Soundfont(e.to_string()))?,
+    );
+    let mut mf_object = midly::Smf::parse(midi).map_err(|e| MidiSynthError::Midi(e.to_string()))?;
+    let midly::Timing::Metrical(tpb) = mf_object.header.timing else {
+        return Err(MidiSynthError::Midi("SMPTE timing not supported".into()));
+    };
+
+    let instr = toml_table(&config, "instr")
+        .and_then(|instr| instrument_index(instr.ok_or("No instruments specified")?))
+        .map_err(invalid)?;
+    let channels = toml_table(&config, "channels").map_err(invalid)?;
+    let order = channel_order(&config).map_err(invalid)?;
+    let quality = RenderQuality::from_toml(&config).map_err(invalid)?;
+    let polyphony = polyphony_from_toml(&config).map_err(invalid)?;
+    let tuning = tuning_from_toml(&config).map_err(invalid)?;
+    if let Some(key) = ["tuning_file", "keyboard_map"]
+        .into_iter()
+        .find(|&key| config.contains_key(key))
+    {
+        return Err(invalid(format!(
+            "{} names a file, which render_midi doesn't read",
+            key
+        )));
+    }
+    let reverb = MixReverb::from_toml(&config).map_err(invalid)?;
+    let buses = MixBus::from_config(&config).map_err(invalid)?;
+    let precision = MixPrecision::from_toml(&config).map_err(invalid)?;
+    let sections = TempoSection::from_toml(&config).map_err(invalid)?;
+
+    // Sequence the MIDI file
+    apply_tempo_sections(&mut mf_object.tracks, &sections, tpb.as_int().into());
+    let mut seq = Sequencer {
+        tracks: mf_object
+            .tracks
+            .into_iter()
+            .map(SequencedTrack::create)
+            .collect(),
+        timemap: TimeMap::default(),
+    };
+    let tracks = seq
+        .play_all(mf_object.header.timing, false, progress)
+        .map_err(MidiSynthError::Midi)?;
+
+    // Render the tracks in parallel
+    let sample_rate = SAMPLE_RATE as usize;
+    let mut renders = plan_renders(
+        &instr,
+        channels,
+        &tracks,
+        &sf_object,
+        &[],
+        false,
+        &mut |_| {},
+    );
+    apply_mutes(&mut renders, &seq.timemap, &mut |_| {});
+    if renders.iter().any(|(_, _, is, _)| is.output > 0) || buses.values().any(|b| b.output > 0) {
+        return Err(invalid(
+            "render_midi only renders stereo, output pairs can't be routed".into(),
+        ));
+    }
+    let shared = std::sync::Arc::new(SharedProgress {
+        sink: Some(progress),
+        ..Default::default()
+    });
+    let failed = |name: &str, e: &dyn std::fmt::Display| {
+        MidiSynthError::Render(format!("Rendering {} failed: {}", name, e))
+    };
+    let mtracks: Vec<_> = std::thread::scope(|scope| {
+        let threads: Vec<_> = renders
+            .into_iter()
+            .map(|(name, part, is, padding)| {
+                let synth_settings = quality.settings(SAMPLE_RATE as i32, polyphony);
+                let synth_object = rustysynth::Synthesizer::new(&sf_object, &synth_settings)
+                    .map_err(|e| failed(&name, &e))?;
+                let pbar = TrackProgress::new(shared.clone());
+                let label = name.clone();
+                let thread = scope.spawn(move || {
+                    let gainfactors = MixerGainFactors::from_setting(&is, &part);
+                    let offset = is.offset(sample_rate);
+                    let mut renderer = Renderer {
+                        synth: synth_object,
+                        track: part,
+                        tuning,
+                        scale: None,
+                        stolen: (0, 0),
+                    };
+                    let (left, right) = renderer.render(&is, padding, None, &pbar);
+
+                    MixerTrack {
+                        name,
+                        track: renderer.track.name.take(),
+                        left,
+                        right,
+                        gainfactors,
+                        offset,
+                        output: is.output,
+                        bus: is.bus,
+                    }
+                });
+                Ok((label, thread))
+            })
+            .collect::<Result<_, _>>()?;
+        threads
+            .into_iter()
+            .map(|(name, t)| {
+                t.join()
+                    .map_err(|_| failed(&name, &"the renderer panicked"))
+            })
+            .collect::<Result<_, _>>()
+    })?;
+    if mtracks.is_empty() {
+        return Err(MidiSynthError::Render("No music was generated".into()));
+    }
+
+    // Mix the tracks
+    let tracks = mix_buses(mtracks, &buses, sample_rate, precision);
+    let mut wavdata = Mixer {
+        tracks,
+        precision,
+        channels: 2,
+    }
+    .mix(progress);
+    if let Some(reverb) = reverb {
+        reverb.apply(&mut wavdata, sample_rate);
+    }
+    reorder_channels(&mut wavdata, &order);
+
+    Ok(wavdata)
+}
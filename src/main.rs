@@ -3,482 +3,2559 @@
 // This file is subject to the terms and conditions defined in file 'LICENSE',
 // which is part of this source code package.
 
+use midisynth_rs::synth::*;
+use midisynth_rs::{Progress, Stage};
 use resolve_path::PathResolveExt;
 use std::io::Read;
 
-struct SequencedTrack<'a> {
-    track: std::vec::IntoIter<midly::TrackEvent<'a>>,
-    next: Option<midly::TrackEvent<'a>>,
-    ticks: u32,
-    count: usize,
-}
-
-impl<'a> SequencedTrack<'a> {
-    fn create(track: midly::Track<'a>) -> Self {
-        let count = track.len();
-        let mut track = Self {
-            track: track.into_iter(),
-            next: None,
-            ticks: 0,
-            count,
-        };
-
-        track.advance();
-
-        track
+/// Describes the notes of a rendered track that had voices stolen, if any, for --verbose.
+fn stolen_voices(label: &str, renderer: &Renderer) -> Option<String> {
+    let (limited, overflows) = renderer.stolen;
+    let mut stolen = Vec::new();
+    if limited > 0 {
+        stolen.push(format!("{} notes stolen beyond max_voices", limited));
     }
-
-    fn advance(&mut self) {
-        self.next = self.track.next();
-        self.ticks = self
-            .next
-            .map_or(u32::MAX, |e| self.ticks + u32::from(e.delta));
+    if overflows > 0 {
+        stolen.push(format!(
+            "{} notes over polyphony, started with {} or more notes held",
+            overflows,
+            renderer.synth.get_maximum_polyphony()
+        ));
     }
+    (!stolen.is_empty()).then(|| format!("      {}: {}", label, stolen.join(", ")))
 }
 
-#[derive(Debug)]
-struct SequencerEvent<'a> {
-    idx: usize,
-    ticks: u32,
-    event: midly::TrackEvent<'a>,
-}
+/// Shows progress on a progress bar, clearing it when done.
+struct BarProgress(indicatif::ProgressBar);
 
-#[derive(Clone, Debug)]
-struct PlayerEvent {
-    time: usize,
-    note: u8,
-    velocity: u8,
+impl Progress for BarProgress {
+    fn update(&self, _stage: Stage, current: u64, total: u64) {
+        self.0.set_length(total);
+        self.0.set_position(current);
+        if current >= total {
+            self.0.finish_and_clear();
+        }
+    }
 }
 
-#[derive(Clone, Debug, Default)]
-struct PlayerTrack {
-    name: Option<String>,
-    length: usize,
-    events: Vec<PlayerEvent>,
+/// The channels drawn in the preview image, in lanes of their own.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum PreviewChannels {
+    Stereo,
+    Mix,
+    Left,
+    Right,
 }
 
-struct Sequencer<'a> {
-    tracks: Vec<SequencedTrack<'a>>,
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Engine {
+    Custom,
+    Rustysynth,
 }
 
-impl<'a> Sequencer<'a> {
-    fn next(&mut self) -> Option<SequencerEvent<'a>> {
-        let (idx, ticks) = self
-            .tracks
-            .iter()
-            .map(|t| t.ticks)
-            .enumerate()
-            .min_by_key(|v| v.1)?;
-
-        if ticks != u32::MAX {
-            let track = &mut self.tracks[idx];
-
-            let event = track.next.unwrap();
-            track.advance();
+/// The levels of a rendered track, along with its resolved gain and placement in the mix.
+struct TrackLevels {
+    name: String,
+    peak: f32, // dBFS
+    rms: f32,  // dBFS
+    gain: f32, // dB
+    placement: String,
+}
 
-            Some(SequencerEvent { idx, ticks, event })
-        } else {
-            None
+impl TrackLevels {
+    fn measure(track: &MixerTrack, gain: f32, placement: String) -> Self {
+        let samples = track.left.iter().chain(track.right.iter());
+        let (peak, energy) = samples.fold((0_f32, 0_f64), |(peak, energy), s| {
+            (peak.max(s.abs()), energy + f64::from(*s) * f64::from(*s))
+        });
+        let count = (track.left.len() + track.right.len()).max(1) as f64;
+        Self {
+            name: track.name.clone(),
+            peak: 20.0 * peak.log10(),
+            rms: (10.0 * (energy / count).log10()) as f32,
+            gain,
+            placement,
         }
     }
+}
 
-    fn play_all(
-        &mut self,
-        timing: midly::Timing,
-        pbar: indicatif::ProgressBar,
-    ) -> Vec<PlayerTrack> {
-        let mut tracks: Vec<_> = std::iter::repeat_with(|| PlayerTrack::default())
-            .take(self.tracks.len())
-            .collect();
-
-        let ticks_per_beat = match timing {
-            midly::Timing::Metrical(tpb) => usize::from(tpb.as_int()),
-            _ => todo!(),
-        };
-        let mut tempo: usize = 500_000;
-
-        let mut base_time: usize = 0;
-        let mut base_ticks: u32 = 0;
-
-        pbar.set_length(self.tracks.iter().map(|t| t.count).sum::<usize>() as u64);
+/// Writes the levels of the rendered tracks, before their gain and placement are applied, as a
+/// table.
+fn write_mix_report(levels: &[TrackLevels], out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    let width = levels
+        .iter()
+        .map(|l| l.name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    writeln!(
+        out,
+        "      {:<width$}  {:>10}  {:>10}  {:>8}  Placement",
+        "Track", "Peak", "RMS", "Gain"
+    )?;
+    for l in levels {
+        writeln!(
+            out,
+            "      {:<width$}  {:>5.1} dBFS  {:>5.1} dBFS  {:>+5.1} dB  {}",
+            l.name, l.peak, l.rms, l.gain, l.placement
+        )?;
+    }
+    out.flush()
+}
 
-        while let Some(e) = self.next() {
-            let delta_ticks = e.ticks - base_ticks;
-            let delta_time = (delta_ticks as usize) * tempo / ticks_per_beat;
+/// The tracks of the main mix and of each additional mix. When summing, the tracks are mixed
+/// into one per bus as they're added, so that only the sums are kept in memory.
+struct MixSet<'a> {
+    targets: &'a [(String, MixTarget)],
+    mixes: Vec<Vec<MixerTrack>>, // main mix first, then one per target
+    sum: bool,
+    levels: Option<Vec<TrackLevels>>, // of the tracks as added, for the mix report
+}
 
-            let time = base_time + delta_time;
+impl<'a> MixSet<'a> {
+    fn new(targets: &'a [(String, MixTarget)], sum: bool, report: bool) -> Self {
+        Self {
+            targets,
+            mixes: vec![Vec::new(); targets.len() + 1],
+            sum,
+            levels: report.then(Vec::new),
+        }
+    }
 
-            match e.event.kind {
-                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(t)) => {
-                    tempo = t.as_int() as usize;
+    fn add(&mut self, track: MixerTrack) {
+        let included = self
+            .targets
+            .iter()
+            .map(|(_, target)| target.includes(track.track.as_deref()));
+        let mixes = std::iter::once(true)
+            .chain(included)
+            .zip(self.mixes.iter_mut())
+            .filter_map(|(included, mix)| included.then_some(mix))
+            .collect::<Vec<_>>();
+
+        if !self.sum {
+            // The main mix takes the track itself, the others get a copy
+            for mix in mixes.into_iter().skip(1) {
+                mix.push(track.clone());
+            }
+            self.mixes[0].push(track);
+            return;
+        }
 
-                    base_time += delta_time;
-                    base_ticks += delta_ticks;
-                }
-                midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(n)) => {
-                    tracks[e.idx].name = String::from_utf8(n.to_vec()).ok();
-                }
-                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) => {
-                    tracks[e.idx].length = time;
-                }
-                midly::TrackEventKind::Midi {
-                    channel: _,
-                    message: midly::MidiMessage::NoteOn { key: k, vel: v },
-                } => {
-                    tracks[e.idx].events.push(PlayerEvent {
-                        time,
-                        note: k.into(),
-                        velocity: v.into(),
+        for mix in mixes {
+            let sum = match mix.iter_mut().find(|t| t.bus == track.bus) {
+                Some(sum) => sum,
+                None => {
+                    mix.push(MixerTrack {
+                        name: match &track.bus {
+                            Some(bus) => format!("{} (tracks)", bus),
+                            None => "Tracks".into(),
+                        },
+                        track: None,
+                        left: Vec::new(),
+                        right: Vec::new(),
+                        gainfactors: MixerGainFactors::balance(0.0, 0.0),
+                        offset: 0,
+                        output: track.output,
+                        bus: track.bus.clone(),
                     });
+                    mix.last_mut().unwrap()
                 }
-                _ => { /* println!("skipping: {:?}", e); */ }
             };
-            pbar.inc(1);
+            sum.accumulate(&track);
         }
+    }
 
-        pbar.finish_and_clear();
-
-        tracks
+    fn is_empty(&self) -> bool {
+        self.mixes[0].is_empty()
     }
 }
 
-#[derive(Debug)]
-struct InstrumentSetting {
-    bank: u8,
-    preset: u8,
-    transpose: Option<i8>,
-    pan: Option<f32>,  // -1 .. 1
-    gain: Option<f32>, // dB
+/// An additional mix of some of the tracks, written to its own file, as configured by the
+/// `[mix.<name>]` table. Without an include list, all tracks but the excluded ones are mixed.
+struct MixTarget {
+    include: Option<Vec<String>>,
+    exclude: Vec<String>,
+    gain: f32, // dB
+    output: std::path::PathBuf,
 }
 
-impl InstrumentSetting {
-    fn from_toml(setting: &toml::Value) -> Result<Self, String> {
-        Ok(Self {
-            bank: setting
-                .get("bank")
-                .and_then(|v| v.as_integer())
-                .and_then(|v| Some(v as u8))
-                .unwrap_or(0),
-
-            preset: setting
-                .get("preset")
-                .and_then(|v| v.as_integer())
-                .and_then(|v| Some(v as u8))
-                .ok_or("Missing preset value")?,
-
-            transpose: setting
-                .get("tsp")
-                .and_then(|v| v.as_integer())
-                .and_then(|v| Some(v as i8)),
-
-            pan: setting.get("pan").and_then(|v| match v {
-                toml::Value::Integer(i) => Some(*i as f32),
-                toml::Value::Float(f) => Some(*f as f32),
-                _ => None,
-            }),
-
-            gain: setting.get("gain").and_then(|v| match v {
-                toml::Value::Integer(i) => Some(*i as f32),
-                toml::Value::Float(f) => Some(*f as f32),
-                _ => None,
-            }),
-        })
+impl MixTarget {
+    fn from_config(config: &toml::Table) -> Result<Vec<(String, Self)>, String> {
+        let Some(mixes) = config.get("mix") else {
+            return Ok(Vec::new());
+        };
+        let mixes = mixes.as_table().ok_or("mix must be a table")?;
+        let names = |mix: &toml::Table, key: &str, name: &str| {
+            mix.get(key)
+                .map(|v| {
+                    v.as_array()
+                        .and_then(|a| a.iter().map(|n| n.as_str().map(String::from)).collect())
+                        .ok_or_else(|| {
+                            format!("{} of mix {} must be a list of track names", key, name)
+                        })
+                })
+                .transpose()
+        };
+        mixes
+            .iter()
+            .map(|(name, mix)| {
+                let mix = mix
+                    .as_table()
+                    .ok_or_else(|| format!("mix {} must be a table", name))?;
+                Ok((
+                    name.clone(),
+                    Self {
+                        include: names(mix, "include", name)?,
+                        exclude: names(mix, "exclude", name)?.unwrap_or_default(),
+                        gain: mix.get("gain").and_then(toml_float).unwrap_or(0.0),
+                        output: mix
+                            .get("output")
+                            .and_then(|v| v.as_str())
+                            .ok_or_else(|| format!("mix {} needs an output file", name))?
+                            .into(),
+                    },
+                ))
+            })
+            .collect()
     }
-}
 
-struct Renderer {
-    synth: rustysynth::Synthesizer,
-    track: PlayerTrack,
+    fn includes(&self, track: Option<&str>) -> bool {
+        let listed = |names: &[String]| track.is_some_and(|t| names.iter().any(|n| n == t));
+        self.include.as_deref().is_none_or(listed) && !listed(&self.exclude)
+    }
 }
 
-impl Renderer {
-    fn render(
-        &mut self,
-        instr: &InstrumentSetting,
-        padding: usize,
-        pbar: indicatif::ProgressBar,
-    ) -> (Vec<f32>, Vec<f32>) {
-        let sr: usize = self.synth.get_sample_rate() as usize;
-        let bs: usize = self.synth.get_block_size();
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Wav8,
+    Wav16,
+    Wav24,
+    WavF32,
+    Flac,
+}
 
-        let sc: usize = ((self.track.length + padding) * sr / 1_000_000).next_multiple_of(bs);
+impl OutputFormat {
+    fn from_path(path: &std::path::Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "wav" => Some(Self::WavF32),
+            "flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+}
 
-        let mut left: Vec<f32> = vec![0_f32; sc];
-        let mut right: Vec<f32> = vec![0_f32; sc];
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "wav:pcm8" => Ok(Self::Wav8),
+            "wav:pcm16" => Ok(Self::Wav16),
+            "wav:pcm24" => Ok(Self::Wav24),
+            "wav" | "wav:f32" => Ok(Self::WavF32),
+            "flac" => Ok(Self::Flac),
+            _ => Err(format!(
+                "invalid format '{}' (valid formats: wav:pcm8, wav:pcm16, wav:pcm24, wav:f32, \
+                 flac)",
+                s
+            )),
+        }
+    }
+}
 
-        let mut it = self.track.events.iter().peekable();
+fn write_output(
+    format: OutputFormat,
+    wavdata: &[f32],
+    sr: i32,
+    channels: u16,
+    path: &std::path::Path,
+    seed: Option<u32>,
+) -> Result<(), String> {
+    match format {
+        OutputFormat::Wav8 => {
+            return write_wav8(wavdata, sr, channels, path, seed).map_err(|e| e.to_string());
+        }
+        OutputFormat::Wav16 => {
+            let samples: wavers::Samples<i16> =
+                wavers::Samples::from(wavdata.to_vec().into_boxed_slice()).convert();
+            wavers::write(path, &samples, sr, channels)
+        }
+        OutputFormat::Wav24 => {
+            // Convert manually, as wavers scales f32 to the full i32 range before narrowing.
+            let samples: Vec<i24::i24> = wavdata
+                .iter()
+                .map(|s| i24::i24::from_i32((s.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32))
+                .collect();
+            wavers::write(path, &samples, sr, channels)
+        }
+        OutputFormat::WavF32 => wavers::write(path, wavdata, sr, channels),
+        OutputFormat::Flac => return write_flac(wavdata, sr, channels, path),
+    }
+    .map_err(|e| e.to_string())
+}
 
-        // Setup instruments using MIDI control messages, as Synthesizer has no API for this.
-        self.synth
-            .process_midi_message(0, 0xb0, 0x00, instr.bank.into());
-        self.synth
-            .process_midi_message(0, 0xc0, instr.preset.into(), 0);
-        let transpose = instr.transpose.unwrap_or(0);
+/// Simple xorshift generator for reproducible dither noise.
+struct Dither(u32);
 
-        pbar.set_length(sc as u64);
+impl Dither {
+    /// Starts the noise from a seed, or else from a fixed one, so that the same mix always gets
+    /// the same dither and renders stay byte-identical either way.
+    fn new(seed: Option<u32>) -> Self {
+        // Spread the seed over the state, which must not be zero
+        Self(seed.map_or(0x2545_f491, |s| s.wrapping_mul(0x9e37_79b9).max(1)))
+    }
 
-        for si in (0..sc).step_by(bs) {
-            let t = (si * 1_000_000) / sr;
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32
+    }
 
-            loop {
-                if let Some(e) = it.peek() {
-                    if e.time <= t {
-                        let note = e.note.strict_add_signed(transpose);
-                        self.synth.note_on(0, note.into(), e.velocity.into());
-                        it.next();
-                        continue;
-                    }
-                }
-                break;
-            }
+    /// Triangular (TPDF) dither noise in the range -1 .. 1 LSB.
+    fn tpdf(&mut self) -> f32 {
+        self.next() + self.next() - 1.0
+    }
+}
 
-            self.synth
-                .render(&mut left[si..si + bs], &mut right[si..si + bs]);
+/// Writes unsigned 8-bit PCM samples with TPDF dither, which wavers does not support.
+fn write_wav8(
+    wavdata: &[f32],
+    sr: i32,
+    channels: u16,
+    path: &std::path::Path,
+    seed: Option<u32>,
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let data_len = wavdata.len() as u32;
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+    out.write_all(b"RIFF")?;
+    // Chunks are padded to even length, the pad byte counting towards the RIFF size only
+    out.write_all(&(36 + data_len + (data_len & 1)).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16_u32.to_le_bytes())?;
+    out.write_all(&1_u16.to_le_bytes())?; // PCM
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&sr.to_le_bytes())?;
+    out.write_all(&(sr * i32::from(channels)).to_le_bytes())?; // byte rate
+    out.write_all(&channels.to_le_bytes())?; // block align
+    out.write_all(&8_u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+
+    let mut dither = Dither::new(seed);
+    let bytes: Vec<u8> = wavdata
+        .iter()
+        .map(|s| {
+            (s * 127.0 + 128.0 + dither.tpdf())
+                .round()
+                .clamp(0.0, 255.0) as u8
+        })
+        .collect();
+    out.write_all(&bytes)?;
+    if data_len & 1 == 1 {
+        out.write_all(&[0])?;
+    }
 
-            pbar.inc(bs as u64);
-        }
-        pbar.finish_and_clear();
+    out.flush()
+}
 
-        (left, right)
+/// Writes 24-bit FLAC.
+fn write_flac(
+    wavdata: &[f32],
+    sr: i32,
+    channels: u16,
+    path: &std::path::Path,
+) -> Result<(), String> {
+    use flacenc::component::BitRepr;
+    use flacenc::error::Verify;
+
+    if !(1..=8).contains(&channels) {
+        return Err(format!("FLAC supports up to 8 channels, not {}", channels));
     }
+    let samples: Vec<i32> = wavdata
+        .iter()
+        .map(|s| (s.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32)
+        .collect();
+    // flacenc misjudges the size of fixed predictors where a 24-bit channel turns into loud
+    // noise, attempting allocations of gigabytes, so only its LPC is used
+    let mut config = flacenc::config::Encoder::default();
+    config.subframe_coding.use_fixed = false;
+    let config = config.into_verified().map_err(|(_, e)| e.to_string())?;
+    let source =
+        flacenc::source::MemSource::from_samples(&samples, channels.into(), 24, sr as usize);
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| e.to_string())?;
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream.write(&mut sink).map_err(|e| e.to_string())?;
+    std::fs::write(path, sink.as_slice()).map_err(|e| e.to_string())
 }
 
-struct MixerGainFactors {
-    l_to_l: f32,
-    l_to_r: f32,
-    r_to_l: f32,
-    r_to_r: f32,
+/// Convolution reverb with a recorded impulse response, given by --ir. Mono impulse responses
+/// apply to both channels, stereo ones per channel.
+struct ConvolutionReverb {
+    ir: [Vec<f32>; 2],
+    wet: f32, // 0 .. 1
 }
 
-impl MixerGainFactors {
-    fn new(gain_db: f32, pan: f32) -> Self {
-        // map pan from [-1 .. 1] to [0 .. π/2] (radians)
-        let pan_rad = (((pan % 1.0) + 1.0) / 2.0) * (std::f32::consts::PI / 2.0);
-        // convert gain from dB to factor
-        let gain = 10f32.powf(gain_db / 20.0);
+impl ConvolutionReverb {
+    /// Loads an impulse response, resampling it to the output rate and scaling it to unit
+    /// energy so that the wet level doesn't depend on its recording level.
+    fn load(path: &std::path::Path, wet: f32, sr: usize) -> Result<Self, String> {
+        let failed = |e: &dyn std::fmt::Display| {
+            format!("Loading impulse response {} failed: {}", path.display(), e)
+        };
+        let mut wav: wavers::Wav<f32> = wavers::Wav::from_path(path).map_err(|e| failed(&e))?;
+        let channels = usize::from(wav.n_channels()).max(1);
+        let ir_sr = wav.sample_rate() as usize;
+        let samples = wav.read().map_err(|e| failed(&e))?;
+        if samples.is_empty() {
+            return Err(failed(&"no samples"));
+        }
 
-        Self {
-            // destination: left (cos curve)
-            l_to_l: gain * pan_rad.cos(),
-            r_to_l: gain * (pan_rad + (std::f32::consts::PI / 4.0)).cos().max(0.0),
+        let mut ir: [Vec<f32>; 2] = std::array::from_fn(|ch| {
+            let ch = ch.min(channels - 1);
+            let ir: Vec<f32> = samples.iter().skip(ch).step_by(channels).copied().collect();
+            if ir_sr == sr {
+                return ir;
+            }
+            // Linear interpolation is good enough for the diffuse tail of a reverb
+            let len = (ir.len() * sr).div_ceil(ir_sr);
+            (0..len)
+                .map(|i| {
+                    let pos = (i * ir_sr) as f64 / sr as f64;
+                    let (idx, frac) = (pos as usize, pos.fract() as f32);
+                    let a = ir[idx.min(ir.len() - 1)];
+                    let b = ir[(idx + 1).min(ir.len() - 1)];
+                    a + (b - a) * frac
+                })
+                .collect()
+        });
+
+        let energy = ir
+            .iter()
+            .map(|ch| ch.iter().map(|s| s * s).sum::<f32>())
+            .fold(0_f32, f32::max);
+        if energy > 0.0 {
+            let scale = energy.sqrt().recip();
+            ir.iter_mut().flatten().for_each(|s| *s *= scale);
+        }
+
+        Ok(Self {
+            ir,
+            wet: wet.clamp(0.0, 1.0),
+        })
+    }
 
-            // destination: right (sin curve)
-            r_to_r: gain * pan_rad.sin(),
-            l_to_r: gain * (pan_rad - (std::f32::consts::PI / 4.0)).sin().max(0.0),
+    /// Convolves interleaved stereo samples with the impulse response, extending them by its
+    /// tail.
+    fn apply(&self, wavdata: &mut Vec<f32>) {
+        let frames = wavdata.len() / 2;
+        let tail = self.ir[0].len().max(self.ir[1].len()) - 1;
+        let wet: [Vec<f32>; 2] = std::array::from_fn(|ch| {
+            let input: Vec<f32> = wavdata.iter().skip(ch).step_by(2).copied().collect();
+            convolve(&input, &self.ir[ch])
+        });
+
+        wavdata.resize((frames + tail) * 2, 0.0);
+        for (i, frame) in wavdata.chunks_exact_mut(2).enumerate() {
+            for (ch, s) in frame.iter_mut().enumerate() {
+                let w = wet[ch].get(i).copied().unwrap_or(0.0);
+                *s = *s * (1.0 - self.wet) + w * self.wet;
+            }
         }
     }
 }
 
-struct MixerTrack {
-    left: Vec<f32>,
-    right: Vec<f32>,
-    gainfactors: MixerGainFactors,
+/// Reports how well the mix survives mono playback: the change of its RMS and peak level when
+/// summed to mono, and the tracks and octave bands that largely cancel.
+fn check_mono(tracks: &[(String, f32)], wavdata: &[f32], sr: usize, warnings: &Warnings) {
+    // Hard-panned material loses 3 dB, so more than twice that means the channels cancel
+    const CANCELS: f32 = -6.0;
+
+    let frames = || wavdata.chunks_exact(2).map(|f| (f[0], f[1]));
+    let Some(loss) = mono_loss(frames()) else {
+        return;
+    };
+    let stereo_peak = wavdata.iter().fold(0_f32, |peak, s| peak.max(s.abs()));
+    let mono_peak = frames().fold(0_f32, |peak, (l, r)| peak.max(((l + r) / 2.0).abs()));
+    println!(
+        "      Mono sum: {:+.1} dB RMS, {:+.1} dB peak versus stereo",
+        loss,
+        20.0 * (mono_peak / stereo_peak).max(1e-6).log10()
+    );
+
+    for (name, loss) in tracks.iter().filter(|t| t.1 < CANCELS) {
+        warnings.print(format!("{} loses {:.1} dB in mono", name, -loss));
+    }
+    let bands: Vec<_> = mono_band_losses(wavdata, sr)
+        .into_iter()
+        .filter(|b| b.2 < CANCELS)
+        .map(|(low, high, loss)| format!("{:.0}-{:.0} Hz ({:.1} dB)", low, high, -loss))
+        .collect();
+    if !bands.is_empty() {
+        warnings.print(format!(
+            "Octaves losing more than {} dB in mono: {}",
+            -CANCELS,
+            bands.join(", ")
+        ));
+    }
 }
 
-struct Mixer {
-    tracks: Vec<MixerTrack>,
+/// Plays interleaved samples through the default audio output device, returning when done.
+#[cfg(feature = "preview")]
+fn preview(wavdata: &[f32], sr: u32, channels: u16) -> Result<(), String> {
+    let stream = rodio::OutputStreamBuilder::open_default_stream().map_err(|e| e.to_string())?;
+    let sink = rodio::Sink::connect_new(stream.mixer());
+    sink.append(rodio::buffer::SamplesBuffer::new(
+        channels,
+        sr,
+        wavdata.to_vec(),
+    ));
+    sink.sleep_until_end();
+    Ok(())
 }
 
-impl Mixer {
-    fn mix_stereo(&self, pbar: indicatif::ProgressBar) -> Vec<f32> {
-        let sc = self.tracks.iter().map(|t| t.left.len()).max().unwrap();
+#[cfg(not(feature = "preview"))]
+fn preview(_wavdata: &[f32], _sr: u32, _channels: u16) -> Result<(), String> {
+    Err("not supported by this build".into())
+}
 
-        let mut out: Vec<f32> = Vec::with_capacity(sc * 2);
+/// Quotes a string for JSON output.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
 
-        pbar.set_length(sc as u64);
+/// Writes the sequenced tracks as JSON, for diagnosing sequencing issues independently of the
+/// synthesizer. Times and durations are in µs.
+fn dump_events(tracks: &[PlayerTrack], path: &std::path::Path) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(out, "[")?;
+    for (i, track) in tracks.iter().enumerate() {
+        let name = track.name.as_deref().map_or("null".into(), json_string);
+        writeln!(out, "  {{")?;
+        writeln!(out, "    \"name\": {},", name)?;
+        writeln!(out, "    \"length\": {},", track.length)?;
+        let bank = track.bank.map_or("null".into(), |b| b.to_string());
+        writeln!(out, "    \"bank\": {},", bank)?;
+        writeln!(out, "    \"events\": [")?;
+        for (j, e) in track.events.iter().enumerate() {
+            writeln!(
+                out,
+                "      {{\"time\": {}, \"duration\": {}, \"channel\": {}, \"note\": {}, \
+                 \"velocity\": {}, \"tempo\": {}}}{}",
+                e.time,
+                e.duration,
+                e.channel,
+                e.note,
+                e.velocity,
+                e.tempo,
+                if j + 1 < track.events.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(out, "    ]")?;
+        writeln!(out, "  }}{}", if i + 1 < tracks.len() { "," } else { "" })?;
+    }
+    writeln!(out, "]")?;
+    out.flush()
+}
 
-        for si in 0..sc {
-            let mut sl: f32 = 0f32; // output sample accumulator to left channel
-            let mut sr: f32 = 0f32; // output sample accumulator to right channel
-            for t in self.tracks.iter() {
-                let gf = &t.gainfactors;
-                let il = t.left.get(si).copied().unwrap_or(0.0); // input from left channel
-                let ir = t.right.get(si).copied().unwrap_or(0.0); // input from right channel
-                sl += gf.l_to_l * il + gf.r_to_l * ir;
-                sr += gf.l_to_r * il + gf.r_to_r * ir;
+/// Writes the lyrics of all tracks as an LRC file of timed lines, with times shifted by `offset`
+/// (µs). Syllables are joined into lines, which karaoke files break with a carriage return or
+/// newline at the end of a syllable or a slash or backslash at its start, and which also break
+/// at rests of more than two seconds. Returns the number of lines.
+fn write_lyrics(
+    tracks: &[PlayerTrack],
+    offset: usize,
+    path: &std::path::Path,
+) -> std::io::Result<usize> {
+    use std::io::Write;
+    const REST: usize = 2_000_000; // µs
+
+    let mut lyrics: Vec<_> = tracks.iter().flat_map(|t| t.lyrics.iter()).collect();
+    lyrics.sort_by_key(|(time, _)| *time);
+
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    let mut open = false;
+    let mut last = 0;
+    for (time, text) in lyrics {
+        let text = match text.strip_prefix(['/', '\\']) {
+            Some(text) => {
+                open = false;
+                text
             }
-            out.push(sl);
-            out.push(sr);
-
-            pbar.inc(1);
+            None => text,
+        };
+        let syllable = text.trim_end_matches(['\r', '\n']);
+        if !syllable.is_empty() {
+            if !open || *time > last + REST {
+                lines.push((*time + offset, String::new()));
+            }
+            lines.last_mut().unwrap().1.push_str(syllable);
+            open = true;
+            last = *time;
         }
+        if syllable.len() < text.len() {
+            open = false;
+        }
+    }
 
-        pbar.finish_and_clear();
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for (time, text) in lines.iter() {
+        let centis = time / 10_000;
+        writeln!(
+            out,
+            "[{:02}:{:02}.{:02}]{}",
+            centis / 6_000,
+            centis / 100 % 60,
+            centis % 100,
+            text.trim()
+        )?;
+    }
+    out.flush()?;
+    Ok(lines.len())
+}
 
-        out
+/// Prints the index, name, MIDI channels and number of notes of each track, which is what the
+/// `instr` and `channels` tables of the configuration are keyed by.
+fn list_tracks(tracks: &[PlayerTrack]) {
+    let names: Vec<_> = tracks
+        .iter()
+        .map(|t| t.name.clone().unwrap_or_else(|| "<unnamed>".into()))
+        .collect();
+    let width = names
+        .iter()
+        .map(|n| n.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+
+    println!(
+        "{:>5}  {:<width$}  {:<12}  {:>6}",
+        "Track", "Name", "Channels", "Notes"
+    );
+    for (idx, (track, name)) in tracks.iter().zip(names).enumerate() {
+        let mut channels: Vec<u8> = track.events.iter().map(|e| e.channel).collect();
+        channels.sort_unstable();
+        channels.dedup();
+        let channels = match channels.is_empty() {
+            true => "-".into(),
+            false => channels
+                .iter()
+                .map(|ch| (ch + 1).to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        println!(
+            "{:>5}  {:<width$}  {:<12}  {:>6}",
+            idx,
+            name,
+            channels,
+            track.events.len()
+        );
     }
 }
 
+/// Parses a size in bytes with an optional K, M, G or T suffix (powers of 1024).
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        Some((i, 't' | 'T')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let value: f64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: {}", s))?;
+    Ok((value * (1_u64 << shift) as f64) as usize)
+}
+
+/// Parses the headroom (in dB) left by normalizing the peak of the mix.
+fn parse_headroom(s: &str) -> Result<f32, String> {
+    s.parse()
+        .ok()
+        .filter(|db| (0.0..=60.0).contains(db))
+        .ok_or_else(|| "headroom must be from 0 to 60 dB".into())
+}
+
+/// Parses an image size such as 1200x300.
+fn parse_image_size(s: &str) -> Result<(usize, usize), String> {
+    let size = s.split_once(['x', 'X']).and_then(|(w, h)| {
+        let w: usize = w.trim().parse().ok().filter(|w| (16..=16384).contains(w))?;
+        let h: usize = h.trim().parse().ok().filter(|h| (16..=8192).contains(h))?;
+        Some((w, h))
+    });
+    size.ok_or_else(|| {
+        format!(
+            "invalid image size: {} (expected WIDTHxHEIGHT, up to 16384x8192)",
+            s
+        )
+    })
+}
+
 mod args {
     #[derive(clap::Parser)]
     #[command(author, version)]
     pub struct Args {
-        /// Configuration file
-        #[arg(short, long)]
-        pub config: clio::Input,
+        /// Configuration file (may be given multiple times, later files override earlier ones)
+        #[arg(short, long, required_unless_present_any = ["channels_to_tracks", "list_tracks"])]
+        pub config: Vec<clio::Input>,
+
+        /// Validate the configuration and soundfont without rendering
+        #[arg(long, conflicts_with = "channels_to_tracks")]
+        pub check: bool,
+
+        /// Soundfont file or URL to use instead of the configured one
+        #[arg(long, value_name = "PATH")]
+        pub soundfont: Option<String>,
+
+        /// Split a type-0 MIDI file into one track per channel and write it as a type-1 MIDI
+        /// file instead of rendering
+        #[arg(long)]
+        pub channels_to_tracks: bool,
+
+        /// Print the index, name, MIDI channels and number of notes of each track instead of
+        /// rendering
+        #[arg(long, conflicts_with_all = ["check", "channels_to_tracks"])]
+        pub list_tracks: bool,
 
         /// Input MIDI file
-        pub midifile: clio::Input,
+        #[arg(required_unless_present = "check")]
+        pub midifile: Option<clio::Input>,
+
+        /// Maximum length of each rendered track (in seconds)
+        #[arg(short, long)]
+        pub duration: Option<f64>,
+
+        /// Normalize each track to a peak level (in dBFS, default -6) before the configured
+        /// instrument gain and pan are applied
+        #[arg(long, value_name = "DBFS", num_args = 0..=1, require_equals = true,
+              default_missing_value = "-6", allow_negative_numbers = true)]
+        pub normalize_per_track: Option<f32>,
+
+        /// Attenuate every track by 10 * log10(N) dB, N being the number of rendered tracks, so
+        /// that the sum of many uncorrelated tracks keeps the level of a single one
+        #[arg(long)]
+        pub auto_headroom: bool,
+
+        /// Synthesis engine: `custom` renders each track separately for per-track mixing,
+        /// `rustysynth` renders the whole file with rustysynth's own sequencer as a reference
+        #[arg(long, value_enum, default_value = "custom")]
+        pub engine: super::Engine,
+
+        /// Change the tempo linearly between tempo events instead of stepwise
+        #[arg(long)]
+        pub smooth_tempo: bool,
+
+        /// Write the left and right channels of the mix to separate mono files, named
+        /// `<name>_L` and `<name>_R`
+        #[arg(long)]
+        pub split_lr: bool,
+
+        /// Start the output with silence up to the SMPTE offset of the MIDI file, so that it
+        /// lines up with the timecode of the cue
+        #[arg(long)]
+        pub honor_smpte_offset: bool,
+
+        /// Skip the synthesizer and render a click for every note, to quickly check timing
+        #[arg(long)]
+        pub dry: bool,
+
+        /// Add convolution reverb to the mix with an impulse response from a WAV file
+        #[arg(long, value_name = "FILE")]
+        pub ir: Option<clio::InputPath>,
+
+        /// Share of the convolution reverb in the mix (0 = dry, 1 = wet only)
+        #[arg(long, value_name = "MIX", default_value_t = 0.3, requires = "ir")]
+        pub ir_wet: f32,
+
+        /// Normalize the mix to an integrated loudness (in LUFS, e.g. -14)
+        #[arg(long, value_name = "TARGET", allow_negative_numbers = true)]
+        pub lufs: Option<f64>,
+
+        /// Normalize the mix to a true peak this far below full scale (in dB, default 1), which
+        /// leaves headroom for later processing and for overs between samples on conversion
+        #[arg(long, value_name = "DB", num_args = 0..=1, require_equals = true,
+              default_missing_value = "1", value_parser = super::parse_headroom,
+              conflicts_with = "lufs")]
+        pub normalize_headroom: Option<f32>,
+
+        /// Add a metronome click track to the mix
+        #[arg(short, long)]
+        pub metronome: bool,
+
+        /// After rendering, re-render whenever a configuration file changes, reusing the tracks
+        /// whose instrument settings other than gain, pan and offset_ms are unchanged
+        #[arg(long)]
+        pub watch: bool,
+
+        /// Write the sequenced notes of all tracks to a JSON file before rendering
+        #[arg(long, value_name = "FILE")]
+        pub dump_events: Option<clio::OutputPath>,
+
+        /// Write the lyrics of the MIDI file to an LRC file of timed lines
+        #[arg(long, value_name = "FILE")]
+        pub lyrics: Option<clio::OutputPath>,
+
+        /// Report the peak and RMS level, gain and placement of each track after rendering, on
+        /// the console or to a file
+        #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true,
+              default_missing_value = "-")]
+        pub mix_report: Option<clio::OutputPath>,
+
+        /// Print the tracks that will be rendered, with their instruments and estimated
+        /// duration, and ask for confirmation before rendering
+        #[arg(long, conflicts_with_all = ["check", "watch"])]
+        pub only_matched: bool,
+
+        /// Render without asking for confirmation after --only-matched
+        #[arg(short, long, requires = "only_matched")]
+        pub yes: bool,
+
+        /// Transpose all instruments by whole octaves, on top of their tsp setting
+        #[arg(long, value_name = "N", allow_negative_numbers = true,
+              value_parser = clap::value_parser!(i8).range(-10..=10))]
+        pub transpose_octaves: Option<i8>,
+
+        /// Report the level change of the mix when summed to mono, and warn about the tracks and
+        /// octave bands that cancel in mono
+        #[arg(long)]
+        pub check_mono: bool,
+
+        /// Only render the tracks with the given names (comma-separated)
+        #[arg(short, long, value_delimiter = ',')]
+        pub tracks: Vec<String>,
+
+        /// Refuse to render if the estimated memory use exceeds this size (e.g. 512M, 2G)
+        #[arg(long, value_parser = super::parse_size)]
+        pub max_memory: Option<usize>,
+
+        /// Show a single aggregate progress bar while rendering instead of one per track
+        #[arg(long)]
+        pub single_bar: bool,
+
+        /// Render the tracks one at a time, mixing each as it completes, to bound peak memory
+        #[arg(long, conflicts_with = "single_bar")]
+        pub sequential: bool,
+
+        /// Render all tracks together block by block, mixing each block as it completes
+        #[arg(long, conflicts_with_all = ["sequential", "single_bar"])]
+        pub stream: bool,
+
+        /// Reduce the effective bit depth of the output (lo-fi effect)
+        #[arg(long, value_name = "BITS")]
+        pub bit_crush: Option<u8>,
+
+        /// Reduce the effective sample rate of the output by holding samples (lo-fi effect)
+        #[arg(long, value_name = "FACTOR")]
+        pub downsample: Option<usize>,
+
+        /// Output sample rate (in Hz)
+        #[arg(short, long, default_value_t = 44100, value_parser = clap::value_parser!(u32).range(16_000..=192_000))]
+        pub sample_rate: u32,
+
+        /// Seed of the dither noise of 8-bit output (default: derived from the hash of the MIDI
+        /// file, soundfont and configuration). Renders with the same seed are byte-identical
+        #[arg(long)]
+        pub seed: Option<u32>,
+
+        /// Fail with a non-zero exit status if there were any warnings
+        #[arg(long)]
+        pub strict: bool,
+
+        /// Print additional information
+        #[arg(short, long)]
+        pub verbose: bool,
 
-        /// Destination WAV file
-        pub wavfile: clio::OutputPath,
+        /// Output format: wav:pcm8, wav:pcm16, wav:pcm24, wav:f32 or flac (default: derived from
+        /// the destination file extension)
+        #[arg(short, long)]
+        pub format: Option<super::OutputFormat>,
+
+        /// Append a hash of the MIDI file, soundfont and configuration to the destination file
+        /// name, to record which inputs produced it
+        #[arg(long)]
+        pub hash_name: bool,
+
+        /// Play the render through the default audio device (the destination file is optional)
+        #[arg(long)]
+        pub preview: bool,
+
+        /// Draw the waveform of the mix to a PNG image, with clipping marked in red
+        #[arg(long, value_name = "FILE")]
+        pub preview_image: Option<clio::OutputPath>,
+
+        /// Size of the preview image, as WIDTHxHEIGHT (a spectrogram adds another HEIGHT)
+        #[arg(long, value_name = "SIZE", default_value = "1200x300",
+              value_parser = super::parse_image_size, requires = "preview_image")]
+        pub preview_size: (usize, usize),
+
+        /// Channels to draw in the preview image
+        #[arg(long, value_enum, default_value = "stereo", requires = "preview_image")]
+        pub preview_channels: super::PreviewChannels,
+
+        /// Add a spectrogram of the mix under the waveform in the preview image
+        #[arg(long, requires = "preview_image")]
+        pub preview_spectrogram: bool,
+
+        /// Destination WAV file (or MIDI file with --channels-to-tracks)
+        #[arg(required_unless_present_any = ["check", "preview", "list_tracks"])]
+        pub wavfile: Option<clio::OutputPath>,
     }
 }
 
-fn resolve(name: &str, relative: &std::path::Path) -> Option<String> {
-    let relative = relative.parent()?.canonicalize().ok()?;
-    let resolved = name.try_resolve_in(relative).ok()?;
-    Some(resolved.to_str().unwrap().to_string())
+/// Resolves a file name from the configuration relative to the directory of the configuration
+/// file. Absolute paths are used unchanged, as are all paths if the configuration is read from
+/// stdin.
+fn resolve(name: &str, cfg_input: &clio::Input) -> std::path::PathBuf {
+    let base = match cfg_input.path().path().parent() {
+        _ if cfg_input.is_std() => None,
+        Some(p) if p.as_os_str().is_empty() => std::path::Path::new(".").canonicalize().ok(),
+        Some(p) => p.canonicalize().ok(),
+        None => None,
+    };
+    base.and_then(|base| name.try_resolve_in(base).ok())
+        .map(|p| p.into_owned())
+        .unwrap_or_else(|| name.into())
 }
 
-fn midisynth() -> Result<(), String> {
-    let mut args = <args::Args as clap::Parser>::parse();
+/// Reports the synthesis rate compared to the native rates of the soundfont's samples.
+fn report_sample_rate(sf: &rustysynth::SoundFont, sample_rate: usize, warnings: &Warnings) {
+    println!("      Synthesizing at {} Hz", sample_rate);
+
+    let rates = sf
+        .get_sample_headers()
+        .iter()
+        .map(|h| h.get_sample_rate() as usize);
+    let (min, max) = rates.fold((usize::MAX, 0), |(lo, hi), r| (lo.min(r), hi.max(r)));
+    if max == 0 {
+        return;
+    }
+    println!(
+        "      Soundfont samples are recorded at {} - {} Hz",
+        min, max
+    );
 
-    // Prepare progress bar style and UI elements
-    let sty = indicatif::ProgressStyle::with_template("      {bar:40.cyan/blue} {msg}")
-        .unwrap()
-        .progress_chars("#>-");
-    let warning = console::style("Warning").yellow().bold();
+    if sample_rate < 44_100 {
+        warnings.print(format!(
+            "{} Hz is below common sample rates (44100, 48000 Hz), expect reduced quality",
+            sample_rate
+        ));
+    } else if sample_rate < max {
+        warnings.print(format!(
+            "Samples recorded at up to {} Hz will be downsampled to {} Hz",
+            max, sample_rate
+        ));
+    }
+}
 
-    // Parse configuration
+fn load_config(input: &mut clio::Input) -> Result<toml::Table, String> {
     let mut s = String::new();
-    args.config
+    input
         .read_to_string(&mut s)
-        .map_err(|e| format!("Reading configuration file {} failed: {}", args.config, e))?;
-    let config = s
+        .map_err(|e| format!("Reading configuration file {} failed: {}", input, e))?;
+    let mut config = s
         .parse::<toml::Table>()
-        .map_err(|e| format!("Parsing configuration file {} failed: {}", args.config, e))?;
-    let instr = config
-        .get("instr")
-        .and_then(|v| v.as_table())
-        .ok_or("Invalid configuration: No instruments specified")?;
+        .map_err(|e| format!("Parsing configuration file {} failed: {}", input, e))?;
 
-    // Load sound font
-    let sf_fname = config
-        .get("soundfont")
-        .and_then(|v| v.as_str())
-        .ok_or("Invalid configuration: No soundfont specified")
-        .map(|s| resolve(&s, args.config.path().path()).unwrap_or(s.to_string()))?;
-
-    let mut sf_file = std::fs::File::open(&sf_fname)
-        .map_err(|e| format!("Opening soundfont file {} failed: {}", sf_fname, e))?;
-    let sf_object = std::sync::Arc::new(
-        rustysynth::SoundFont::new(&mut sf_file)
-            .map_err(|e| format!("Loading soundfont file {} failed: {}", sf_fname, e))?,
+    // Resolve file names now, while it is known which configuration file they came from
+    if let Some(toml::Value::String(name)) = config.get_mut("soundfont")
+        && !is_remote(name)
+    {
+        *name = resolve(name, input).to_string_lossy().into_owned();
+    }
+    for key in ["tuning_file", "keyboard_map"] {
+        if let Some(toml::Value::String(name)) = config.get_mut(key) {
+            *name = resolve(name, input).to_string_lossy().into_owned();
+        }
+    }
+    if let Some(toml::Value::Table(mixes)) = config.get_mut("mix") {
+        for (_, mix) in mixes.iter_mut() {
+            if let Some(toml::Value::String(name)) = mix.get_mut("output") {
+                *name = resolve(name, input).to_string_lossy().into_owned();
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// Merges `other` into `base`: tables are merged recursively, any other value (including the
+/// instrument lists of a track) is replaced.
+fn merge_config(base: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge_config(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Loads and merges all configuration files in order, later files overriding earlier ones. A
+/// soundfont given on the command line overrides the configured one.
+fn load_configs(
+    inputs: &mut [clio::Input],
+    soundfont: Option<&str>,
+) -> Result<toml::Table, String> {
+    if inputs.is_empty() {
+        return Err("No configuration file specified".into());
+    }
+    let mut config = toml::Table::new();
+    for input in inputs.iter_mut() {
+        merge_config(&mut config, load_config(input)?);
+    }
+    if let Some(name) = soundfont {
+        config.insert("soundfont".into(), toml::Value::String(name.into()));
+    }
+    Ok(config)
+}
+
+/// Returns whether a soundfont name refers to an http(s) URL rather than a file.
+fn is_remote(name: &str) -> bool {
+    ["http://", "https://"]
+        .iter()
+        .any(|scheme| name.starts_with(scheme))
+}
+
+/// Downloads a soundfont into the cache directory, unless an earlier download is found there,
+/// and returns the path of the cached file.
+fn fetch_soundfont(url: &str) -> Result<std::path::PathBuf, String> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| std::path::Path::new(&h).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("midisynth-rs");
+
+    let basename = url
+        .rsplit('/')
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("soundfont");
+    // Name the file by a stable hash of the URL, so that later runs and builds find it
+    let hash = fnv1a(FNV_OFFSET, url.as_bytes());
+    let path = cache.join(format!("{:016x}-{}", hash, basename));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let failed = |e: &dyn std::fmt::Display| format!("Downloading soundfont {} failed: {}", url, e);
+
+    let mut response = ureq::get(url).call().map_err(|e| failed(&e))?;
+    let length = response
+        .headers()
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    let pbar = match length {
+        Some(length) => indicatif::ProgressBar::new(length),
+        None => indicatif::ProgressBar::no_length(),
+    };
+    pbar.set_style(
+        indicatif::ProgressStyle::with_template("      {bar:40.cyan/blue} {bytes}/{total_bytes}")
+            .unwrap()
+            .progress_chars("#>-"),
     );
 
+    let mut data = Vec::new();
+    std::io::copy(
+        &mut pbar.wrap_read(response.body_mut().with_config().limit(u64::MAX).reader()),
+        &mut data,
+    )
+    .map_err(|e| failed(&e))?;
+    pbar.finish_and_clear();
+
+    // A soundfont is a RIFF file of form type 'sfbk'
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+        return Err(failed(&"not a SoundFont 2 file"));
+    }
+
+    // Write to a temporary file first, so that an interrupted write doesn't poison the cache
+    let partial = path.with_extension("part");
+    std::fs::create_dir_all(&cache)
+        .and_then(|_| std::fs::write(&partial, &data))
+        .and_then(|_| std::fs::rename(&partial, &path))
+        .map_err(|e| format!("Caching soundfont {} failed: {}", path.display(), e))?;
+
+    Ok(path)
+}
+
+/// Returns the file of the configured soundfont, downloading it if needed.
+fn soundfont_file(config: &toml::Table) -> Result<std::path::PathBuf, String> {
+    let sf_name = match config.get("soundfont") {
+        Some(toml::Value::String(name)) => name,
+        Some(v) => {
+            return Err(format!(
+                "Invalid configuration: soundfont must be a string, not {}",
+                toml_type(v)
+            ));
+        }
+        None => return Err("Invalid configuration: No soundfont specified".into()),
+    };
+
+    if is_remote(sf_name) {
+        fetch_soundfont(sf_name)
+    } else {
+        Ok(std::path::PathBuf::from(sf_name))
+    }
+}
+
+fn load_soundfont(config: &toml::Table) -> Result<std::sync::Arc<rustysynth::SoundFont>, String> {
+    let sf_fname = soundfont_file(config)?;
+    let mut sf_file = std::fs::File::open(&sf_fname).map_err(|e| {
+        format!(
+            "Opening soundfont file {} failed: {}",
+            sf_fname.display(),
+            e
+        )
+    })?;
+    Ok(std::sync::Arc::new(
+        rustysynth::SoundFont::new(&mut sf_file).map_err(|e| {
+            format!(
+                "Loading soundfont file {} failed: {}",
+                sf_fname.display(),
+                e
+            )
+        })?,
+    ))
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Folds bytes into a 64-bit FNV-1a hash, which unlike `DefaultHasher` is stable across runs and
+/// builds.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Hashes formatted text with FNV-1a, to tell values apart by their `Debug` output without
+/// keeping it.
+struct FnvWriter(u64);
+
+impl std::fmt::Write for FnvWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0 = fnv1a(self.0, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Returns a hash of the MIDI file, soundfont and configuration that produced a render.
+fn input_hash(mf_data: &[u8], config: &toml::Table) -> Result<u64, String> {
+    let sf_fname = soundfont_file(config)?;
+    let sf_data = std::fs::read(&sf_fname).map_err(|e| {
+        format!(
+            "Reading soundfont file {} failed: {}",
+            sf_fname.display(),
+            e
+        )
+    })?;
+
+    let mut hash = FNV_OFFSET;
+    for data in [mf_data, &sf_data, config.to_string().as_bytes()] {
+        // Prefix each input with its length, so that moving bytes between them changes the hash
+        hash = fnv1a(fnv1a(hash, &(data.len() as u64).to_le_bytes()), data);
+    }
+    Ok(hash)
+}
+
+/// Returns the hash of the inputs, if the output needs it: to name the output with --hash-name,
+/// or to seed the dither of 8-bit output without --seed.
+fn output_hash(
+    args: &args::Args,
+    mf_data: &[u8],
+    config: &toml::Table,
+) -> Result<Option<u64>, String> {
+    let dithered = args.seed.is_none() && args.format == Some(OutputFormat::Wav8);
+    (args.hash_name || dithered)
+        .then(|| input_hash(mf_data, config))
+        .transpose()
+}
+
+/// Validates the configuration and soundfont without rendering, collecting all errors found.
+fn check(args: &mut args::Args) -> Result<(), String> {
+    let config = load_configs(&mut args.config, args.soundfont.as_deref())?;
+    let sf_object = load_soundfont(&config)?;
+    let cfg_names = args
+        .config
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut errors = Vec::new();
+
+    let instr = toml_table(&config, "instr").unwrap_or_else(|msg| {
+        errors.push(msg);
+        None
+    });
+    if config.get("instr").is_none() {
+        errors.push("No instruments specified".to_string());
+    }
+    let channels = toml_table(&config, "channels").unwrap_or_else(|msg| {
+        errors.push(msg);
+        None
+    });
+    if let Err(msg) = channel_order(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = RenderQuality::from_toml(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = polyphony_from_toml(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = tuning_from_toml(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = Scale::from_config(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = MixReverb::from_toml(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = MixBus::from_config(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = MixTarget::from_config(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = TempoSection::from_toml(&config) {
+        errors.push(msg);
+    }
+    if let Err(msg) = MixPrecision::from_toml(&config) {
+        errors.push(msg);
+    }
+
+    let entries = instr
+        .into_iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v))
+        .chain(
+            channels
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| (format!("channel {}", k), v)),
+        );
+
+    for (track_name, settings) in entries {
+        let settings = match instrument_list(&track_name, settings) {
+            Ok(settings) => settings,
+            Err(msg) => {
+                errors.push(msg);
+                continue;
+            }
+        };
+        if let Err(msg) = xfade_pair(settings) {
+            errors.push(format!("{} for {}", msg, track_name));
+        }
+        for setting in settings {
+            let bank_configured = setting.get("bank").is_some();
+            match InstrumentSetting::from_toml(setting).and_then(|mut is| {
+                is.resolve_preset_name(&sf_object, bank_configured)?;
+                Ok(is)
+            }) {
+                Err(msg) => errors.push(format!("{} for {}", msg, track_name)),
+                Ok(is) => {
+                    let found = sf_object.get_presets().iter().any(|p| {
+                        p.get_bank_number() == i32::from(is.bank)
+                            && p.get_patch_number() == i32::from(is.preset)
+                    });
+                    if !found {
+                        errors.push(format!(
+                            "Preset {}:{} for {} not found in soundfont",
+                            is.bank, is.preset, track_name
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        println!("Configuration {} is valid", cfg_names);
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid configuration {}:\n{}",
+            cfg_names,
+            errors
+                .iter()
+                .map(|e| format!("  - {}", e))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))
+    }
+}
+
+fn midisynth() -> Result<(), String> {
+    let mut args = <args::Args as clap::Parser>::parse();
+
+    if args.check {
+        return check(&mut args);
+    }
+
     // Load MIDI file
+    let midifile = args.midifile.as_mut().ok_or("No MIDI file specified")?;
+    let wavfile = args.wavfile.as_ref();
+    if wavfile.is_none() && !args.preview && !args.list_tracks {
+        return Err("No destination file specified".into());
+    }
+    if args.preview && !cfg!(feature = "preview") {
+        return Err("Audio preview is not supported by this build".into());
+    }
     let mut mf_data = Vec::new();
-    args.midifile
+    midifile
         .read_to_end(&mut mf_data)
-        .map_err(|e| format!("Reading MIDI file {} failed: {}", args.midifile, e))?;
+        .map_err(|e| format!("Reading MIDI file {} failed: {}", midifile, e))?;
     let mf_object = midly::Smf::parse(&mf_data)
-        .map_err(|e| format!("Loading MIDI file {} failed: {}", args.midifile, e))?;
+        .map_err(|e| format!("Loading MIDI file {} failed: {}", midifile, e))?;
+
+    if args.channels_to_tracks {
+        let wavfile = wavfile.ok_or("No destination file specified")?;
+        let names: Vec<_> = (1..=16).map(|ch| format!("Channel {}", ch)).collect();
+        let split = split_channels(&mf_object, &names)?;
+        split
+            .save(wavfile.path().path())
+            .map_err(|e| format!("Writing output MIDI file {} failed: {}", wavfile, e))?;
+        return Ok(());
+    }
+
+    if args.list_tracks {
+        let mut seq = Sequencer {
+            tracks: mf_object
+                .tracks
+                .into_iter()
+                .map(SequencedTrack::create)
+                .collect(),
+            timemap: TimeMap::default(),
+        };
+        let tracks = seq
+            .play_all(mf_object.header.timing, false, &())
+            .map_err(|e| format!("Loading MIDI file {} failed: {}", midifile, e))?;
+        list_tracks(&tracks);
+        return Ok(());
+    }
+
+    // Parse configuration
+    let mut config = load_configs(&mut args.config, args.soundfont.as_deref())?;
+
+    if args.engine == Engine::Rustysynth {
+        return render_reference(&args, &config, &mf_data);
+    }
+
+    let mf_name = midifile.to_string();
+    let mut cache = RenderCache::new();
+    loop {
+        // The configuration may change the tempo map, so sequence again on every reload
+        let warnings = Warnings::default();
+        let result =
+            sequence(&args, &mf_name, &mf_object, &config).and_then(|(tracks, timemap)| {
+                render_and_mix(
+                    &args, &config, &mf_data, &tracks, &timemap, &mut cache, &warnings,
+                )
+            });
+        match result.and_then(|()| warnings.check(args.strict)) {
+            Err(msg) if args.watch => {
+                println!("{}: {}", console::style("Error").red().bold(), msg)
+            }
+            result => result?,
+        }
+        if !args.watch {
+            return Ok(());
+        }
+
+        // Wait for the configuration to change, then render again, reusing unchanged tracks
+        println!("Watching configuration for changes (press Ctrl-C to exit)...");
+        wait_for_change(&args.config)?;
+        for input in args.config.iter_mut() {
+            *input = clio::Input::new(input.path().clone())
+                .map_err(|e| format!("Reopening configuration file {} failed: {}", input, e))?;
+        }
+        match load_configs(&mut args.config, args.soundfont.as_deref()) {
+            Ok(c) => config = c,
+            Err(msg) => println!("{}: {}", console::style("Error").red().bold(), msg),
+        }
+    }
+}
+
+/// Sequences the MIDI file with the tempo map corrected as configured, and writes the event
+/// dump and lyrics if requested.
+fn sequence(
+    args: &args::Args,
+    mf_name: &str,
+    mf_object: &midly::Smf,
+    config: &toml::Table,
+) -> Result<(Vec<PlayerTrack>, TimeMap), String> {
+    let sty = indicatif::ProgressStyle::with_template("      {bar:40.cyan/blue} {msg}")
+        .unwrap()
+        .progress_chars("#>-");
+
+    // Correct the tempo map of the MIDI file as configured
+    let sections =
+        TempoSection::from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let mut mf_tracks = mf_object.tracks.clone();
+    if let midly::Timing::Metrical(tpb) = mf_object.header.timing {
+        apply_tempo_sections(&mut mf_tracks, &sections, tpb.as_int().into());
+    }
 
     // Sequence MIDI file
     println!("[1/3] Sequencing MIDI file...");
     let pbar = indicatif::ProgressBar::no_length();
-    pbar.set_style(sty.clone());
+    pbar.set_style(sty);
+    let pbar = BarProgress(pbar);
 
     let mut seq = Sequencer {
-        tracks: mf_object
-            .tracks
-            .into_iter()
-            .map(|t| SequencedTrack::create(t))
-            .collect(),
+        tracks: mf_tracks.into_iter().map(SequencedTrack::create).collect(),
+        timemap: TimeMap::default(),
+    };
+
+    let tracks = seq
+        .play_all(mf_object.header.timing, args.smooth_tempo, &pbar)
+        .map_err(|e| format!("Loading MIDI file {} failed: {}", mf_name, e))?;
+    if let Some(ref path) = args.dump_events {
+        dump_events(&tracks, path.path())
+            .map_err(|e| format!("Writing event dump {} failed: {}", path, e))?;
+    }
+    if let Some(ref path) = args.lyrics {
+        let offset = match args.honor_smpte_offset {
+            true => seq.timemap.smpte_offset.unwrap_or(0),
+            false => 0,
+        };
+        match write_lyrics(&tracks, offset, path.path()) {
+            Ok(0) => println!("      No lyrics found, {} is empty", path),
+            Ok(lines) => println!("      Wrote {} lines of lyrics to {}", lines, path),
+            Err(e) => return Err(format!("Writing lyrics {} failed: {}", path, e)),
+        }
+    }
+
+    Ok((tracks, seq.timemap))
+}
+
+/// Collects the warnings of a run, so that --strict can fail it if there were any.
+#[derive(Default)]
+struct Warnings {
+    count: std::cell::Cell<usize>,
+}
+
+impl Warnings {
+    /// Returns the line reporting a warning, for printing above progress bars.
+    fn format(&self, msg: impl std::fmt::Display) -> String {
+        self.count.set(self.count.get() + 1);
+        format!(
+            "      {}: {}",
+            console::style("Warning").yellow().bold(),
+            msg
+        )
+    }
+
+    fn print(&self, msg: impl std::fmt::Display) {
+        println!("{}", self.format(msg));
+    }
+
+    fn check(&self, strict: bool) -> Result<(), String> {
+        match self.count.get() {
+            1 if strict => Err("A warning occurred in strict mode".into()),
+            n if strict && n > 0 => Err(format!("{} warnings occurred in strict mode", n)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rendered tracks by name, notes, soundfont and render-affecting settings, reused by --watch
+/// when only mix settings change.
+type RenderCache = std::collections::HashMap<String, (Vec<f32>, Vec<f32>)>;
+
+/// Blocks until the modification time of any of the configuration files changes.
+fn wait_for_change(inputs: &[clio::Input]) -> Result<(), String> {
+    let mtimes = || -> Result<Vec<_>, String> {
+        inputs
+            .iter()
+            .map(|input| {
+                if input.is_std() {
+                    return Err("Cannot watch configuration from standard input".to_string());
+                }
+                std::fs::metadata(input.path().path())
+                    .and_then(|m| m.modified())
+                    .map_err(|e| format!("Watching configuration file {} failed: {}", input, e))
+            })
+            .collect()
+    };
+
+    let initial = mtimes()?;
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        // Files may be briefly missing while an editor saves them
+        if let Ok(current) = mtimes()
+            && current != initial
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Prints the parts that will be rendered, with their instrument, gain (in dB, including
+/// `headroom`), placement and estimated duration.
+fn print_plan(
+    renders: &[(String, PlayerTrack, InstrumentSetting, usize)],
+    sf: &rustysynth::SoundFont,
+    limit: Option<usize>,
+    headroom: f32,
+) {
+    let width = renders
+        .iter()
+        .map(|(name, ..)| name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+
+    println!(
+        "      {:<width$}  {:>9}  {:<20}  {:>8}  {:<14}  Duration",
+        "Track", "Preset", "Name", "Gain", "Placement"
+    );
+    for (part_name, part, is, padding) in renders {
+        let preset = sf
+            .get_presets()
+            .iter()
+            .find(|p| {
+                p.get_bank_number() == i32::from(is.bank)
+                    && p.get_patch_number() == i32::from(is.preset)
+            })
+            .map_or("<missing>", |p| p.get_name());
+        let length = render_length(part, is, *padding, limit) as f64 / 1_000_000.0;
+        println!(
+            "      {:<width$}  {:>9}  {:<20}  {:>+5.1} dB  {:<14}  {}:{:04.1}",
+            part_name,
+            format!("{}:{}", is.bank, is.preset),
+            preset,
+            is.gain.unwrap_or(0.0) + headroom,
+            MixerGainFactors::placement(is, part),
+            (length / 60.0) as usize,
+            length % 60.0
+        );
+    }
+}
+
+/// Asks whether to go ahead on the console. Anything but yes, including the end of input,
+/// declines.
+fn confirm(question: &str) -> bool {
+    use std::io::Write;
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok();
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Renders the tracks with the instruments of the configuration, then mixes and writes them.
+fn render_and_mix(
+    args: &args::Args,
+    config: &toml::Table,
+    mf_data: &[u8],
+    tracks: &[PlayerTrack],
+    timemap: &TimeMap,
+    cache: &mut RenderCache,
+    warnings: &Warnings,
+) -> Result<(), String> {
+    let sty = indicatif::ProgressStyle::with_template("      {bar:40.cyan/blue} {msg}")
+        .unwrap()
+        .progress_chars("#>-");
+
+    let instr = toml_table(config, "instr")
+        .and_then(|instr| instrument_index(instr.ok_or("No instruments specified")?))
+        .map_err(|e| format!("Invalid configuration: {}", e))?;
+    let quality =
+        RenderQuality::from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let polyphony =
+        polyphony_from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let tuning = tuning_from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let scale = Scale::from_config(config)
+        .map_err(|e| format!("Invalid configuration: {}", e))?
+        .map(std::sync::Arc::new);
+    let reverb =
+        MixReverb::from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let buses = MixBus::from_config(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let targets =
+        MixTarget::from_config(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+
+    // Load sound font
+    let sf_object = load_soundfont(config)?;
+    let sf_identity = {
+        let path = soundfont_file(config)?;
+        let meta = std::fs::metadata(&path).ok();
+        let mtime = meta.as_ref().and_then(|m| m.modified().ok());
+        format!("{}@{:?}+{:?}", path.display(), mtime, meta.map(|m| m.len()))
     };
 
-    let tracks = seq.play_all(mf_object.header.timing, pbar);
+    let sample_rate = args.sample_rate as usize;
+    if args.verbose {
+        report_sample_rate(&sf_object, sample_rate, warnings);
+    }
+    if timemap.tempo_conflicts > 0 {
+        warnings.print(format!(
+            "Tempo changes conflicting with simultaneous ones on lower tracks: {} (ignored)",
+            timemap.tempo_conflicts
+        ));
+    }
+
+    let mut effects = MasterEffects {
+        reverb,
+        convolution: match &args.ir {
+            Some(path) => Some(ConvolutionReverb::load(
+                path.path(),
+                args.ir_wet,
+                sample_rate,
+            )?),
+            None => None,
+        },
+        order: channel_order(config).map_err(|e| format!("Invalid configuration: {}", e))?,
+        precision: MixPrecision::from_toml(config)
+            .map_err(|e| format!("Invalid configuration: {}", e))?,
+        lead_in: match args.honor_smpte_offset {
+            true => timemap.smpte_offset.unwrap_or(0) * sample_rate / 1_000_000,
+            false => 0,
+        },
+        channels: 2,
+    };
+
+    let song_length = tracks.iter().map(|t| t.length).max().unwrap_or(0);
 
     // Render tracks
     let mpbar = indicatif::MultiProgress::new();
     mpbar.println("[2/3] Rendering tracks...").ok();
 
+    let limit = args.duration.map(|d| (d.max(0.0) * 1_000_000.0) as usize);
+
+    let shared = args
+        .single_bar
+        .then(|| std::sync::Arc::new(SharedProgress::default()));
+
     let mut threads = Vec::new();
 
-    for (idx, track) in tracks.into_iter().enumerate() {
-        if let Some(ref track_name) = track.name {
-            let settings = match instr.get(track_name).and_then(|v| v.as_array()) {
-                Some(s) => s,
-                None => {
-                    if idx != 0 {
-                        mpbar
-                            .println(format!(
-                                "      {}: No instruments defined for {}, skipping track!",
-                                warning, track_name
-                            ))
-                            .ok();
-                    }
-                    continue;
-                }
-            };
+    let channels =
+        toml_table(config, "channels").map_err(|e| format!("Invalid configuration: {}", e))?;
+    let mut warn = |msg: String| {
+        mpbar.println(warnings.format(msg)).ok();
+    };
+    let mut renders = plan_renders(
+        &instr,
+        channels,
+        tracks,
+        &sf_object,
+        &args.tracks,
+        !args.dry,
+        &mut warn,
+    );
+    apply_mutes(&mut renders, timemap, &mut warn);
+    if let Some(octaves) = args.transpose_octaves {
+        for (_, _, is, _) in renders.iter_mut() {
+            is.transpose = Some(is.transpose.unwrap_or(0).saturating_add(octaves * 12));
+        }
+    }
+
+    // Routing tracks or their buses to further channel pairs makes the output multichannel
+    let pairs = renders
+        .iter()
+        .map(
+            |(_, _, is, _)| match is.bus.as_ref().and_then(|b| buses.get(b)) {
+                Some(bus) => bus.output,
+                None => is.output,
+            },
+        )
+        .max()
+        .unwrap_or(0)
+        + 1;
+    if pairs > 1 {
+        if effects.reverb.is_some()
+            || effects.convolution.is_some()
+            || config.contains_key("channel_order")
+            || args.lufs.is_some()
+            || args.normalize_headroom.is_some()
+            || args.split_lr
+            || args.preview
+            || args.preview_image.is_some()
+            || args.stream
+            || args.sequential
+            || args.check_mono
+        {
+            return Err(format!(
+                "Reverb, --ir, channel_order, --lufs, --normalize-headroom, --split-lr, \
+                 --preview, --preview-image, --stream, --sequential and --check-mono only \
+                 support stereo, not the {} channels that output routes to",
+                pairs * 2
+            ));
+        }
+        effects.channels = pairs * 2;
+        effects.order = (0..effects.channels).collect();
+    }
+
+    // Estimate the peak memory use: the stereo buffers of all tracks plus the mixed output
+    let sample_bytes = 2 * std::mem::size_of::<f32>();
+    let sample_counts: Vec<usize> = renders
+        .iter()
+        .map(|(_, part, is, padding)| {
+            render_length(part, is, *padding, limit) * sample_rate / 1_000_000
+        })
+        .collect();
+    let mix_bytes = sample_counts.iter().max().unwrap_or(&0) * sample_bytes;
+    let track_bytes = match (args.stream, args.sequential) {
+        // Only a few blocks of each track are held at once
+        (true, _) => 0,
+        // One track at a time, summed into each mix and bus as it completes
+        (false, true) => mix_bytes * (1 + (targets.len() + 1) * (buses.len() + 1)),
+        (false, false) => sample_counts.iter().sum::<usize>() * sample_bytes,
+    };
+    let estimate = track_bytes + mix_bytes;
+    if let Some(max_memory) = args.max_memory
+        && estimate > max_memory
+    {
+        return Err(format!(
+            "Rendering {} tracks needs an estimated {} MiB, exceeding the limit of {} MiB \
+             (use --duration or --tracks to render less)",
+            renders.len(),
+            estimate.div_ceil(1 << 20),
+            max_memory >> 20
+        ));
+    }
+
+    // Uncorrelated tracks add up in power, so N tracks are 10 * log10(N) dB louder than one
+    let headroom = match args.auto_headroom {
+        true => -10.0 * (renders.len().max(1) as f32).log10(),
+        false => 0.0,
+    };
+
+    if args.only_matched {
+        print_plan(&renders, &sf_object, limit, headroom);
+        if !args.yes && !confirm(&format!("Render {} tracks?", renders.len())) {
+            return Err("Rendering cancelled".into());
+        }
+    }
 
-            for setting in settings {
-                let is = match InstrumentSetting::from_toml(setting) {
-                    Ok(is) => is,
-                    Err(msg) => {
-                        mpbar
-                            .println(format!(
-                                "      {}: {msg} for {}, skipping track!",
-                                warning, track_name
-                            ))
-                            .ok();
-                        continue;
+    if args.stream {
+        if !targets.is_empty()
+            || args.normalize_per_track.is_some()
+            || args.mix_report.is_some()
+            || args.check_mono
+        {
+            return Err(
+                "Mixes, --normalize-per-track, --mix-report and --check-mono aren't supported \
+                 with --stream"
+                    .into(),
+            );
+        }
+        // Tracks are streamed in the order the mixer sums them: those without a bus, the
+        // metronome, then the buses
+        let mut stracks = Vec::new();
+        let mut groups: Vec<(String, Vec<StreamTrack>)> = Vec::new();
+        for (part_name, part, is, padding) in renders {
+            // Envelopes hold each note until the next starts, which needs more lookahead
+            if is.attack.is_some()
+                || is.decay.is_some()
+                || is.sustain.is_some()
+                || is.release.is_some()
+            {
+                return Err(format!(
+                    "Envelopes of {} aren't supported with --stream",
+                    part_name
+                ));
+            }
+            let gainfactors = MixerGainFactors::from_setting(&is, &part).with_gain(headroom);
+            let offset = is.offset(sample_rate);
+            let group = match is.bus.clone() {
+                Some(name) => match groups.iter().position(|g| g.0 == name) {
+                    Some(i) => &mut groups[i].1,
+                    None => {
+                        groups.push((name, Vec::new()));
+                        &mut groups.last_mut().unwrap().1
                     }
+                },
+                None => &mut stracks,
+            };
+            group.push(if args.dry {
+                let length = render_length(&part, &is, padding, limit);
+                let (left, right) = render_clicks(&part, sample_rate, length);
+                StreamTrack::buffer(left, right, gainfactors, offset)
+            } else {
+                let synth_settings = quality.settings(sample_rate as i32, polyphony);
+                let renderer = Renderer {
+                    synth: rustysynth::Synthesizer::new(&sf_object, &synth_settings).unwrap(),
+                    track: part,
+                    tuning,
+                    scale: scale.clone(),
+                    stolen: (0, 0),
                 };
+                StreamTrack::synth(renderer, is, padding, limit, gainfactors, offset)
+            });
+        }
+        if stracks.is_empty() && groups.is_empty() {
+            return Err("No music was generated".into());
+        }
+        let count = stracks.len() + groups.iter().map(|g| g.1.len()).sum::<usize>();
+        if args.metronome {
+            let (left, right) = render_metronome(timemap, sample_rate, song_length + PADDING);
+            stracks.push(StreamTrack::buffer(
+                left,
+                right,
+                MixerGainFactors::new(0.0, 0.0),
+                0,
+            ));
+        }
+        for (name, tracks) in groups {
+            stracks.push(StreamTrack::bus(
+                tracks,
+                buses.get(&name),
+                sample_rate,
+                effects.precision,
+            ));
+        }
 
-                let synth_settings = rustysynth::SynthesizerSettings::new(44100);
-                let synth_object =
-                    rustysynth::Synthesizer::new(&sf_object, &synth_settings).unwrap();
+        let pbar = mpbar.add(indicatif::ProgressBar::no_length());
+        pbar.set_style(sty.clone());
+        pbar.set_message(format!("{} tracks", count));
+        let wavdata = stream_mix(stracks, effects.precision, &BarProgress(pbar));
+
+        println!("[3/3] Mixing...");
+        let clipping = wavdata
+            .chunks_exact(2)
+            .filter(|f| f[0].abs().max(f[1].abs()) > 1.0);
+        let clipping = clipping.count();
+        if clipping > 0 {
+            warnings.print(format!("Mix clips in {} samples", clipping));
+        }
+        let hash = output_hash(args, mf_data, config)?;
+        let wavdata = finish(args, wavdata, &effects, 0.0, warnings);
+        return write_mix(args, &wavdata, sample_rate, 2, hash);
+    }
 
+    let mut mixes = MixSet::new(&targets, args.sequential, args.mix_report.is_some());
+    // Adds a track to the mixes, with its gain (in dB) and placement for the mix report
+    let add = |mixes: &mut MixSet, mut mtrack: MixerTrack, (gain, placement): (f32, String)| {
+        if let Some(target_db) = args.normalize_per_track {
+            mtrack.normalize(target_db);
+        }
+        if headroom != 0.0 {
+            mtrack.gainfactors = mtrack.gainfactors.with_gain(headroom);
+        }
+        if let Some(levels) = mixes.levels.as_mut() {
+            levels.push(TrackLevels::measure(&mtrack, gain + headroom, placement));
+        }
+        mixes.add(mtrack);
+    };
+
+    // Sequential rendering reuses a single synth and shows a single bar for all tracks
+    let mut synth = None;
+    let seqbar = args.sequential.then(|| {
+        let pbar = mpbar.add(indicatif::ProgressBar::new(
+            sample_counts.iter().sum::<usize>() as u64,
+        ));
+        pbar.set_style(sty.clone());
+        pbar.set_message(format!("{} tracks", renders.len()));
+        pbar
+    });
+    let mut rendered = 0;
+    let mut used = std::collections::HashSet::new();
+
+    for (part_name, part, is, padding) in renders {
+        let label = format!("{} ({}:{})", part_name, is.bank, is.preset);
+        let gainfactors = MixerGainFactors::from_setting(&is, &part);
+        let offset = is.offset(sample_rate);
+        let setting = (
+            is.gain.unwrap_or(0f32),
+            MixerGainFactors::placement(&is, &part),
+        );
+
+        // Everything but the mix settings affects the rendered track, including the notes as
+        // sequenced, which tempo sections change, and the soundfont
+        let mut notes = FnvWriter(FNV_OFFSET);
+        std::fmt::Write::write_fmt(&mut notes, format_args!("{:?}", part)).ok();
+        let key = format!(
+            "{}|{:016x}|{}|{:?}|{:?}|{}|{:?}|{}|{:?}",
+            part_name,
+            notes.0,
+            sf_identity,
+            quality,
+            polyphony,
+            tuning,
+            scale,
+            padding,
+            InstrumentSetting {
+                gain: None,
+                pan: None,
+                balance: None,
+                offset_ms: None,
+                output: 0,
+                bus: None,
+                ..is.clone()
+            }
+        );
+        used.insert(key.clone());
+        if let Some((left, right)) = cache.get(&key) {
+            add(
+                &mut mixes,
+                MixerTrack {
+                    name: label,
+                    track: part.name.clone(),
+                    left: left.clone(),
+                    right: right.clone(),
+                    gainfactors,
+                    offset,
+                    output: is.output,
+                    bus: is.bus.clone(),
+                },
+                setting,
+            );
+            continue;
+        }
+
+        if args.dry {
+            let length = render_length(&part, &is, padding, limit);
+            let (left, right) = render_clicks(&part, sample_rate, length);
+            add(
+                &mut mixes,
+                MixerTrack {
+                    name: label,
+                    track: part.name.clone(),
+                    left,
+                    right,
+                    gainfactors,
+                    offset,
+                    output: is.output,
+                    bus: is.bus.clone(),
+                },
+                setting,
+            );
+            continue;
+        }
+
+        let synth_settings = quality.settings(sample_rate as i32, polyphony);
+        let synth_object = match synth.take() {
+            Some(synth) => synth,
+            None => rustysynth::Synthesizer::new(&sf_object, &synth_settings).unwrap(),
+        };
+
+        if let Some(ref seqbar) = seqbar {
+            let mut renderer = Renderer {
+                synth: synth_object,
+                track: part,
+                tuning,
+                scale: scale.clone(),
+                stolen: (0, 0),
+            };
+            let base = seqbar.position();
+            let progress = |_, current, _| seqbar.set_position(base + current);
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                renderer.render(&is, padding, limit, &progress)
+            }));
+            rendered += 1;
+            let Ok((left, right)) = result else {
+                // Don't reuse a synth left in an unknown state
+                mpbar
+                    .println(
+                        warnings.format(format!("Rendering {} failed, dropping track!", label)),
+                    )
+                    .ok();
+                continue;
+            };
+            if args.verbose
+                && let Some(stolen) = stolen_voices(&label, &renderer)
+            {
+                mpbar.println(stolen).ok();
+            }
+            renderer.synth.reset();
+            synth = Some(renderer.synth);
+
+            if args.watch {
+                cache.insert(key, (left.clone(), right.clone()));
+            }
+            add(
+                &mut mixes,
+                MixerTrack {
+                    name: label,
+                    track: renderer.track.name.take(),
+                    left,
+                    right,
+                    gainfactors,
+                    offset,
+                    output: is.output,
+                    bus: is.bus,
+                },
+                setting,
+            );
+            continue;
+        }
+
+        let pbar: Box<dyn Progress + Send> = match shared {
+            Some(ref shared) => Box::new(TrackProgress::new(shared.clone())),
+            None => {
                 let pbar = mpbar.add(indicatif::ProgressBar::no_length());
                 pbar.set_style(sty.clone());
-                pbar.set_message(track_name.clone());
+                pbar.set_message(part_name.clone());
+                Box::new(BarProgress(pbar))
+            }
+        };
 
-                let mut renderer = Renderer {
-                    synth: synth_object,
-                    track: track.clone(),
-                };
+        let mut renderer = Renderer {
+            synth: synth_object,
+            track: part,
+            tuning,
+            scale: scale.clone(),
+            stolen: (0, 0),
+        };
+
+        let name = label.clone();
+        let thread_handle = std::thread::spawn(move || {
+            let (left, right) = renderer.render(&is, padding, limit, &*pbar);
+            let stolen = stolen_voices(&name, &renderer);
+
+            let mtrack = MixerTrack {
+                name,
+                track: renderer.track.name.take(),
+                left,
+                right,
+                gainfactors,
+                offset,
+                output: is.output,
+                bus: is.bus,
+            };
+            (mtrack, stolen)
+        });
 
-                let thread_handle = std::thread::spawn(move || {
-                    let (left, right) = renderer.render(&is, 1_500_000, pbar);
+        threads.push((label, key, setting, thread_handle));
+    }
 
-                    MixerTrack {
-                        left,
-                        right,
-                        gainfactors: MixerGainFactors::new(
-                            is.gain.unwrap_or(0f32),
-                            is.pan.unwrap_or(0f32),
-                        ),
-                    }
-                });
+    if let Some(seqbar) = seqbar {
+        seqbar.finish_and_clear();
+    }
+
+    if threads.is_empty() && rendered == 0 && mixes.is_empty() {
+        return Err("No music was generated".into());
+    }
 
-                threads.push(thread_handle);
+    if let Some(shared) = shared {
+        let pbar = mpbar.add(indicatif::ProgressBar::no_length());
+        pbar.set_style(sty.clone());
+        pbar.set_message(format!("{} tracks", threads.len()));
+        while !threads.iter().all(|(_, _, _, t)| t.is_finished()) {
+            pbar.set_length(shared.total.load(std::sync::atomic::Ordering::Relaxed));
+            pbar.set_position(shared.done.load(std::sync::atomic::Ordering::Relaxed));
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        pbar.finish_and_clear();
+    }
+
+    for (label, key, setting, thread_handle) in threads {
+        match thread_handle.join() {
+            Ok((mtrack, stolen)) => {
+                if args.verbose
+                    && let Some(stolen) = stolen
+                {
+                    mpbar.println(stolen).ok();
+                }
+                if args.watch {
+                    cache.insert(key, (mtrack.left.clone(), mtrack.right.clone()));
+                }
+                add(&mut mixes, mtrack, setting);
+            }
+            Err(_) => {
+                mpbar
+                    .println(
+                        warnings.format(format!("Rendering {} failed, dropping track!", label)),
+                    )
+                    .ok();
             }
         }
     }
 
-    if threads.len() == 0 {
-        return Err("No music was generated".into());
+    // Drop the renders of tracks and settings no longer configured, which can't be reused
+    cache.retain(|key, _| used.contains(key));
+
+    if mixes.is_empty() {
+        return Err("Rendering failed for all tracks".into());
+    }
+
+    if args.metronome {
+        let (left, right) = render_metronome(timemap, sample_rate, song_length + PADDING);
+        mixes.add(MixerTrack {
+            name: "Metronome".into(),
+            track: None,
+            left,
+            right,
+            gainfactors: MixerGainFactors::new(0.0, 0.0),
+            offset: 0,
+            output: 0,
+            bus: None,
+        });
     }
 
-    let mtracks = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    if let (Some(path), Some(levels)) = (&args.mix_report, &mixes.levels) {
+        let mut out = path
+            .clone()
+            .create()
+            .map_err(|e| format!("Writing mix report {} failed: {}", path, e))?;
+        write_mix_report(levels, &mut out)
+            .map_err(|e| format!("Writing mix report {} failed: {}", path, e))?;
+    }
 
     // Mix tracks
     println!("[3/3] Mixing...");
+    let hash = output_hash(args, mf_data, config)?;
+
+    // Additional mixes of some of the tracks reuse the rendered tracks
+    let mut mixes = mixes.mixes.into_iter();
+    let mtracks = mixes.next().unwrap();
+    for ((name, target), tracks) in targets.iter().zip(mixes) {
+        if tracks.is_empty() {
+            warnings.print(format!("Mix {} has no tracks, skipping!", name));
+            continue;
+        }
+        let pbar = indicatif::ProgressBar::no_length();
+        pbar.set_style(sty.clone());
+        pbar.set_message(name.clone());
+        let wavdata = master(args, tracks, &buses, &effects, target.gain, pbar, warnings);
+        write_file(
+            args,
+            &wavdata,
+            sample_rate,
+            effects.channels,
+            &target.output,
+            hash,
+        )?;
+    }
+
+    let mono_losses: Vec<_> = match args.check_mono {
+        true => mtracks
+            .iter()
+            .filter_map(|t| Some((t.name.clone(), t.mono_loss()?)))
+            .collect(),
+        false => Vec::new(),
+    };
+
     let pbar = indicatif::ProgressBar::no_length();
     pbar.set_style(sty.clone());
+    let wavdata = master(args, mtracks, &buses, &effects, 0.0, pbar, warnings);
+    if args.check_mono {
+        check_mono(&mono_losses, &wavdata, sample_rate, warnings);
+    }
+    write_mix(args, &wavdata, sample_rate, effects.channels, hash)
+}
+
+/// The effects applied to the mix.
+struct MasterEffects {
+    reverb: Option<MixReverb>,
+    convolution: Option<ConvolutionReverb>,
+    lead_in: usize,    // samples of silence before the mix
+    order: Vec<usize>, // output channel order
+    precision: MixPrecision,
+    channels: usize, // two per channel pair tracks are routed to
+}
+
+/// Mixes the tracks through their buses, applies the master effects and gain (in dB), and
+/// returns the interleaved stereo output.
+fn master(
+    args: &args::Args,
+    tracks: Vec<MixerTrack>,
+    buses: &std::collections::HashMap<String, MixBus>,
+    effects: &MasterEffects,
+    gain_db: f32,
+    pbar: indicatif::ProgressBar,
+    warnings: &Warnings,
+) -> Vec<f32> {
+    let sample_rate = args.sample_rate as usize;
+
+    let tracks = mix_buses(tracks, buses, sample_rate, effects.precision);
+    let mixer = Mixer {
+        tracks,
+        precision: effects.precision,
+        channels: effects.channels,
+    };
+    let wavdata = mixer.mix(&BarProgress(pbar));
+
+    let offenders = mixer.clipping(&wavdata);
+    if !offenders.is_empty() {
+        warnings.print(format!(
+            "Mix clips in {} samples, mostly caused by:",
+            offenders.iter().map(|o| o.1).sum::<usize>()
+        ));
+        for (name, count, level, si) in offenders.iter().take(3) {
+            let secs = si / sample_rate;
+            println!(
+                "        {} ({} samples, {:+.1} dBFS at {}:{:02})",
+                name,
+                count,
+                level,
+                secs / 60,
+                secs % 60
+            );
+        }
+    }
+
+    finish(args, wavdata, effects, gain_db, warnings)
+}
+
+/// Applies the master effects and gain (in dB) to the interleaved stereo mix.
+fn finish(
+    args: &args::Args,
+    mut wavdata: Vec<f32>,
+    effects: &MasterEffects,
+    gain_db: f32,
+    warnings: &Warnings,
+) -> Vec<f32> {
+    let sample_rate = args.sample_rate as usize;
+
+    if effects.lead_in > 0 {
+        wavdata.splice(
+            0..0,
+            std::iter::repeat_n(0.0, effects.lead_in * effects.channels),
+        );
+    }
+    if let Some(reverb) = &effects.reverb {
+        reverb.apply(&mut wavdata, sample_rate);
+    }
+    if let Some(convolution) = &effects.convolution {
+        convolution.apply(&mut wavdata);
+    }
+
+    if gain_db != 0.0 {
+        let gain = 10f32.powf(gain_db / 20.0);
+        wavdata.iter_mut().for_each(|s| *s *= gain);
+    }
+
+    if let Some(target) = args.lufs {
+        match loudness(&wavdata, sample_rate) {
+            Some(measured) => {
+                let gain = 10f64.powf((target - measured) / 20.0) as f32;
+                wavdata.iter_mut().for_each(|s| *s *= gain);
+
+                let peak = true_peak(&wavdata);
+                if peak > 1.0 {
+                    warnings.print(format!(
+                        "True peak at {:+.1} dBTP after normalizing to {} LUFS!",
+                        20.0 * peak.log10(),
+                        target
+                    ));
+                }
+            }
+            None => warnings.print("Mix is silent, not normalizing loudness!"),
+        }
+    }
+
+    // The true peak includes the overs between samples that sample peaks miss
+    if let Some(headroom) = args.normalize_headroom {
+        let peak = true_peak(&wavdata);
+        if peak > 0.0 {
+            let gain = 10f32.powf(-headroom / 20.0) / peak;
+            wavdata.iter_mut().for_each(|s| *s *= gain);
+        } else {
+            warnings.print("Mix is silent, not normalizing its peak!");
+        }
+    }
+
+    lofi(
+        &mut wavdata,
+        effects.channels,
+        args.bit_crush,
+        args.downsample,
+    );
+    reorder_channels(&mut wavdata, &effects.order);
+
+    wavdata
+}
+
+/// Writes the mix to the destination file and/or plays it, as requested.
+fn write_mix(
+    args: &args::Args,
+    wavdata: &[f32],
+    sample_rate: usize,
+    channels: usize,
+    hash: Option<u64>,
+) -> Result<(), String> {
+    if let Some(wavfile) = args.wavfile.as_ref() {
+        write_file(args, wavdata, sample_rate, channels, wavfile.path(), hash)?;
+    }
+
+    if let Some(path) = args.preview_image.as_ref() {
+        write_preview_image(
+            path.path(),
+            wavdata,
+            sample_rate,
+            args.preview_size,
+            args.preview_channels,
+            args.preview_spectrogram,
+        )
+        .map_err(|e| format!("Writing preview image {} failed: {}", path, e))?;
+    }
+
+    if args.preview {
+        preview(wavdata, sample_rate as u32, 2)
+            .map_err(|e| format!("Playing preview failed: {}", e))?;
+    }
+
+    Ok(())
+}
 
-    let mixer = Mixer { tracks: mtracks };
-    let wavdata = mixer.mix_stereo(pbar);
+/// Draws the waveform of the mix, and optionally a spectrogram under it, to a PNG image. Each
+/// column of the waveform spans the peaks of its samples, in red where they clip.
+fn write_preview_image(
+    path: &std::path::Path,
+    wavdata: &[f32],
+    sample_rate: usize,
+    (width, height): (usize, usize),
+    channels: PreviewChannels,
+    spectrogram: bool,
+) -> std::io::Result<()> {
+    let left = || wavdata.iter().step_by(2).copied();
+    let right = || wavdata.iter().skip(1).step_by(2).copied();
+    let mono: Vec<f32> = left().zip(right()).map(|(l, r)| (l + r) / 2.0).collect();
+    let lanes: Vec<Vec<f32>> = match channels {
+        PreviewChannels::Stereo => vec![left().collect(), right().collect()],
+        PreviewChannels::Mix => vec![mono.clone()],
+        PreviewChannels::Left => vec![left().collect()],
+        PreviewChannels::Right => vec![right().collect()],
+    };
+
+    let rows = if spectrogram { height * 2 } else { height };
+    let mut img = vec![0_u8; width * rows * 3];
+    let mut plot = |x: usize, y: usize, rgb: [u8; 3]| {
+        img[(y * width + x) * 3..][..3].copy_from_slice(&rgb);
+    };
+
+    let lane_height = height / lanes.len();
+    for (lane, samples) in lanes.iter().enumerate() {
+        let top = lane * lane_height;
+        let half = lane_height as f32 / 2.0;
+        let to_y = |v: f32| (top as f32 + half - v.clamp(-1.0, 1.0) * half).round() as usize;
+        for x in 0..width {
+            let bucket = &samples[x * samples.len() / width..(x + 1) * samples.len() / width];
+            let (min, max) = bucket
+                .iter()
+                .fold((0_f32, 0_f32), |(min, max), &s| (min.min(s), max.max(s)));
+            let color = match min.abs().max(max.abs()) >= 1.0 {
+                true => [240, 60, 50],
+                false => [80, 170, 255],
+            };
+            for y in top..top + lane_height {
+                plot(x, y, [20, 20, 28]);
+            }
+            plot(x, top + lane_height / 2, [60, 60, 70]);
+            for y in to_y(max)..=to_y(min).min(top + lane_height - 1) {
+                plot(x, y, color);
+            }
+        }
+    }
+
+    if spectrogram {
+        // Hann-windowed frames around each column, on a logarithmic axis from 20 Hz up
+        const FRAME: usize = 2048;
+        let window: Vec<f32> = (0..FRAME)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FRAME as f32).cos())
+            .collect();
+        let nyquist = sample_rate as f32 / 2.0;
+        for x in 0..width {
+            let start = (x * mono.len() / width).saturating_sub(FRAME / 2);
+            let mut re: Vec<f32> = (0..FRAME)
+                .map(|i| mono.get(start + i).copied().unwrap_or(0.0) * window[i])
+                .collect();
+            let mut im = vec![0_f32; FRAME];
+            fft(&mut re, &mut im, false);
+            for y in 0..height {
+                let f = 20.0 * (nyquist / 20.0).powf(1.0 - (y as f32 + 0.5) / height as f32);
+                let bin = ((f / nyquist * (FRAME / 2) as f32) as usize).min(FRAME / 2 - 1);
+                // The window halves the amplitude of a sine, and its energy is split over
+                // positive and negative frequencies
+                let amplitude = re[bin].hypot(im[bin]) * 4.0 / FRAME as f32;
+                let db = 20.0 * amplitude.max(1e-10).log10();
+                plot(x, height + y, heat((db + 100.0) / 100.0));
+            }
+        }
+    }
+
+    write_png(path, width, rows, &img)
+}
+
+/// Maps a value from 0 to 1 onto a black, blue, red, yellow and white color ramp.
+fn heat(v: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 160.0],
+        [200.0, 0.0, 80.0],
+        [255.0, 160.0, 0.0],
+        [255.0, 255.0, 255.0],
+    ];
+    let p = v.clamp(0.0, 1.0) * 4.0;
+    let i = (p as usize).min(3);
+    let t = p - i as f32;
+    std::array::from_fn(|c| (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * t).round() as u8)
+}
 
-    let wav_fname: &std::path::Path = args.wavfile.path();
-    wavers::write(wav_fname, &wavdata, 44100, 2)
-        .map_err(|e| format!("Writing output WAV file {} failed: {}", args.wavfile, e))?;
+/// Writes an 8-bit RGB image as a PNG file.
+fn write_png(
+    path: &std::path::Path,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    // Each row starts with its filter type, none
+    let mut idat = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    for row in rgb.chunks_exact(width * 3) {
+        idat.write_all(&[0])?;
+        idat.write_all(row)?;
+    }
+    let idat = idat.finish()?;
+
+    let mut ihdr = Vec::new();
+    ihdr.extend((width as u32).to_be_bytes());
+    ihdr.extend((height as u32).to_be_bytes());
+    ihdr.extend([8, 2, 0, 0, 0]); // bit depth, RGB, compression, filter, interlace
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    for (kind, data) in [
+        (b"IHDR", &ihdr[..]),
+        (b"IDAT", &idat[..]),
+        (b"IEND", &[][..]),
+    ] {
+        out.write_all(&(data.len() as u32).to_be_bytes())?;
+        out.write_all(kind)?;
+        out.write_all(data)?;
+        let mut crc = flate2::Crc::new();
+        crc.update(kind);
+        crc.update(data);
+        out.write_all(&crc.sum().to_be_bytes())?;
+    }
+    out.flush()
+}
 
+/// Writes the mix to a file. With --hash-name, the hash of the inputs is appended to the file
+/// name. Without --seed, the hash also seeds the dither, so that the same inputs always get the
+/// same dither.
+fn write_file(
+    args: &args::Args,
+    wavdata: &[f32],
+    sample_rate: usize,
+    channels: usize,
+    path: &std::path::Path,
+    hash: Option<u64>,
+) -> Result<(), String> {
+    let mut wav_fname = path.to_path_buf();
+    let seed = args.seed.or(hash.map(|h| (h ^ h >> 32) as u32));
+    if let Some(hash) = hash.filter(|_| args.hash_name) {
+        let stem = wav_fname.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{}-{:012x}", stem, hash >> 16);
+        if let Some(ext) = wav_fname.extension() {
+            name = format!("{}.{}", name, ext.to_string_lossy());
+        }
+        wav_fname.set_file_name(name);
+        println!("      Writing {}", wav_fname.display());
+    }
+    let format = args
+        .format
+        .or_else(|| OutputFormat::from_path(&wav_fname))
+        .unwrap_or(OutputFormat::WavF32);
+
+    if !args.split_lr {
+        return write_output(
+            format,
+            wavdata,
+            sample_rate as i32,
+            channels as u16,
+            &wav_fname,
+            seed,
+        )
+        .map_err(|e| format!("Writing output file {} failed: {}", wav_fname.display(), e));
+    }
+
+    // Write each channel to a mono file of its own, with the channel appended to the name
+    for (channel, suffix) in ["L", "R"].into_iter().enumerate() {
+        let stem = wav_fname.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{}_{}", stem, suffix);
+        if let Some(ext) = wav_fname.extension() {
+            name = format!("{}.{}", name, ext.to_string_lossy());
+        }
+        let fname = wav_fname.with_file_name(name);
+        let samples: Vec<f32> = wavdata.iter().skip(channel).step_by(2).copied().collect();
+        write_output(format, &samples, sample_rate as i32, 1, &fname, seed)
+            .map_err(|e| format!("Writing output file {} failed: {}", fname.display(), e))?;
+    }
     Ok(())
 }
 
+/// Renders the whole MIDI file with rustysynth's own sequencer into a single stereo mix, as a
+/// reference for the per-track pipeline. Instrument settings don't apply.
+fn render_reference(args: &args::Args, config: &toml::Table, mf_data: &[u8]) -> Result<(), String> {
+    let quality =
+        RenderQuality::from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let polyphony =
+        polyphony_from_toml(config).map_err(|e| format!("Invalid configuration: {}", e))?;
+    let sf_object = load_soundfont(config)?;
+    let midi_file = rustysynth::MidiFile::new(&mut &mf_data[..])
+        .map_err(|e| format!("Loading MIDI file failed: {}", e))?;
+
+    let sample_rate = args.sample_rate as usize;
+    let synth =
+        rustysynth::Synthesizer::new(&sf_object, &quality.settings(sample_rate as i32, polyphony))
+            .map_err(|e| format!("Creating synthesizer failed: {}", e))?;
+    let mut sequencer = rustysynth::MidiFileSequencer::new(synth);
+    sequencer.play(&std::sync::Arc::new(midi_file), false);
+
+    let mut length = sequencer.get_midi_file().unwrap().get_length() + PADDING as f64 / 1e6;
+    if let Some(duration) = args.duration {
+        length = length.min(duration.max(0.0));
+    }
+    let sc = (length * sample_rate as f64) as usize;
+
+    println!("[1/2] Rendering with rustysynth...");
+    let pbar = indicatif::ProgressBar::new(sc as u64);
+    pbar.set_style(
+        indicatif::ProgressStyle::with_template("      {bar:40.cyan/blue} {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    let mut left = vec![0_f32; sc];
+    let mut right = vec![0_f32; sc];
+    for (l, r) in left
+        .chunks_mut(sample_rate)
+        .zip(right.chunks_mut(sample_rate))
+    {
+        sequencer.render(l, r);
+        pbar.inc(l.len() as u64);
+    }
+    pbar.finish_and_clear();
+
+    println!("[2/2] Writing output...");
+    let wavdata: Vec<f32> = left
+        .iter()
+        .zip(right.iter())
+        .flat_map(|(&l, &r)| [l, r])
+        .collect();
+    let hash = output_hash(args, mf_data, config)?;
+    write_mix(args, &wavdata, sample_rate, 2, hash)
+}
+
 fn main() -> std::process::ExitCode {
     match midisynth() {
         Ok(_) => std::process::ExitCode::SUCCESS,
@@ -488,3 +2565,194 @@ fn main() -> std::process::ExitCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let name = format!("midisynth-rs-{}-{}", std::process::id(), name);
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    /// Decodes a FLAC file into the sample rate, channel count and interleaved samples.
+    fn read_flac(path: &std::path::Path) -> (u32, usize, Vec<i32>) {
+        let mut reader = claxon::FlacReader::open(path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.bits_per_sample, 24);
+        let samples = reader.samples().collect::<Result<_, _>>().unwrap();
+        (info.sample_rate, info.channels as usize, samples)
+    }
+
+    #[test]
+    fn later_configs_override() {
+        let base: toml::Table = r#"
+            soundfont = "base.sf2"
+            quality = "high"
+            [[instr.Piano]]
+            preset = 0
+            gain = -3
+            [[instr.Strings]]
+            preset = 48
+            [reverb]
+            room_size = 0.5
+            level = 0.2
+        "#
+        .parse()
+        .unwrap();
+        let other: toml::Table = r#"
+            soundfont = "other.sf2"
+            [[instr.Piano]]
+            preset = 1
+            [reverb]
+            level = 0.4
+        "#
+        .parse()
+        .unwrap();
+        let mut config = base.clone();
+        merge_config(&mut config, other);
+
+        // Tables merge key by key, other values (the layers of a track too) are replaced
+        let expected: toml::Table = r#"
+            soundfont = "other.sf2"
+            quality = "high"
+            [[instr.Piano]]
+            preset = 1
+            [[instr.Strings]]
+            preset = 48
+            [reverb]
+            room_size = 0.5
+            level = 0.4
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config, expected);
+
+        // A value replaces a table and vice versa, and merging nothing changes nothing
+        let mut config = base.clone();
+        merge_config(&mut config, "reverb = false".parse().unwrap());
+        assert_eq!(config["reverb"], toml::Value::Boolean(false));
+        merge_config(&mut config, "[reverb]\nlevel = 0.1".parse().unwrap());
+        assert_eq!(
+            config["reverb"],
+            toml::Value::Table("level = 0.1".parse().unwrap())
+        );
+        let mut config = base.clone();
+        merge_config(&mut config, toml::Table::new());
+        assert_eq!(config, base);
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        // Published FNV-1a test vectors, which cached downloads are named by
+        assert_eq!(fnv1a(FNV_OFFSET, b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(FNV_OFFSET, b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(FNV_OFFSET, b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn flac_round_trip() {
+        // Silence, a tone, and full-scale noise, which the predictors can't compress, over
+        // several blocks and a partial one
+        let mut noise = Dither::new(Some(7));
+        let wavdata: Vec<f32> = (0..3 * (2 * 4096 + 123))
+            .map(|i| match i / (2 * 4096) {
+                0 => 0.0,
+                1 => (i as f32 * 0.01).sin() * 0.5,
+                _ => noise.next() * 2.0 - 1.0,
+            })
+            .collect();
+        let file = TempFile::new("round-trip.flac");
+        write_output(OutputFormat::Flac, &wavdata, 48000, 3, &file.0, None).unwrap();
+
+        let (sr, channels, samples) = read_flac(&file.0);
+        assert_eq!((sr, channels), (48000, 3));
+        let expected: Vec<i32> = wavdata
+            .iter()
+            .map(|s| (s * 8_388_607.0).round() as i32)
+            .collect();
+        assert_eq!(samples, expected);
+
+        // More blocks than fit a single byte frame number
+        let wavdata = vec![0.25; 130 * 4096];
+        write_output(OutputFormat::Flac, &wavdata, 44100, 1, &file.0, None).unwrap();
+        let (sr, channels, samples) = read_flac(&file.0);
+        assert_eq!((sr, channels), (44100, 1));
+        assert_eq!(samples, vec![2_097_152; wavdata.len()]);
+    }
+
+    #[test]
+    fn wav_round_trip() {
+        // A ramp over full scale in three channels, of an odd number of samples so that 8-bit
+        // data needs a pad byte
+        let wavdata: Vec<f32> = (0..3 * 1001).map(|i| i as f32 / 1501.0 - 1.0).collect();
+        let file = TempFile::new("round-trip.wav");
+
+        // wavers reads 24-bit samples as f32 scaled by the i32 range, so read the integers
+        fn read<T: wavers::AudioSample>(path: &std::path::Path) -> Vec<T>
+        where
+            i16: wavers::ConvertTo<T>,
+            i24::i24: wavers::ConvertTo<T>,
+            i32: wavers::ConvertTo<T>,
+            f32: wavers::ConvertTo<T>,
+            f64: wavers::ConvertTo<T>,
+        {
+            let mut wav: wavers::Wav<T> = wavers::Wav::from_path(path).unwrap();
+            assert_eq!((wav.sample_rate(), wav.n_channels()), (48000, 3));
+            wav.read().unwrap().to_vec()
+        }
+        let scaled =
+            |full: f32| -> Vec<i32> { wavdata.iter().map(|s| (s * full).round() as i32).collect() };
+
+        write_output(OutputFormat::Wav16, &wavdata, 48000, 3, &file.0, None).unwrap();
+        let samples: Vec<i32> = read::<i16>(&file.0).into_iter().map(i32::from).collect();
+        assert_eq!(samples, scaled(32767.0));
+        write_output(OutputFormat::Wav24, &wavdata, 48000, 3, &file.0, None).unwrap();
+        let samples: Vec<i32> = read::<i24::i24>(&file.0)
+            .iter()
+            .map(|s| s.to_i32())
+            .collect();
+        assert_eq!(samples, scaled(8_388_607.0));
+        write_output(OutputFormat::WavF32, &wavdata, 48000, 3, &file.0, None).unwrap();
+        assert_eq!(read::<f32>(&file.0), wavdata);
+
+        // wavers doesn't read 8-bit data, so parse the chunks here
+        write_output(OutputFormat::Wav8, &wavdata, 48000, 3, &file.0, None).unwrap();
+        let bytes = std::fs::read(&file.0).unwrap();
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        assert_eq!(
+            (&bytes[0..4], &bytes[8..16]),
+            (&b"RIFF"[..], &b"WAVEfmt "[..])
+        );
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!((u16_at(20), u16_at(22), u32_at(24)), (1, 3, 48000));
+        assert_eq!((u32_at(28), u16_at(32), u16_at(34)), (3 * 48000, 3, 8));
+        assert_eq!(
+            (&bytes[36..40], u32_at(40) as usize),
+            (&b"data"[..], wavdata.len())
+        );
+        assert_eq!(bytes.len(), 44 + wavdata.len() + 1);
+        for (&read, written) in bytes[44..44 + wavdata.len()].iter().zip(&wavdata) {
+            // Dither adds up to an LSB to the rounding
+            let read = (f32::from(read) - 128.0) / 127.0;
+            assert!(
+                (read - written).abs() <= 1.5 / 127.0,
+                "{} != {}",
+                read,
+                written
+            );
+        }
+    }
+}
@@ -0,0 +1,4997 @@
+// Copyright (C) 2026 Mike Kuyper <mike@kuyper.us>. All rights reserved.
+//
+// This file is subject to the terms and conditions defined in file 'LICENSE',
+// which is part of this source code package.
+
+//! The rendering engine behind [`render_midi`](crate::render_midi) and the command line tool:
+//! sequencing, instrument settings, synthesis, effects and mixing.
+
+use crate::{Progress, Stage};
+
+pub struct SequencedTrack<'a> {
+    pub track: std::vec::IntoIter<midly::TrackEvent<'a>>,
+    pub next: Option<midly::TrackEvent<'a>>,
+    pub ticks: u64,
+    pub count: usize,
+}
+
+impl<'a> SequencedTrack<'a> {
+    pub fn create(track: midly::Track<'a>) -> Self {
+        let count = track.len();
+        let mut track = Self {
+            track: track.into_iter(),
+            next: None,
+            ticks: 0,
+            count,
+        };
+
+        track.advance();
+
+        track
+    }
+
+    pub fn advance(&mut self) {
+        self.next = self.track.next();
+        self.ticks = self
+            .next
+            .map_or(u64::MAX, |e| self.ticks + u64::from(e.delta.as_int()));
+    }
+}
+
+#[derive(Debug)]
+pub struct SequencerEvent<'a> {
+    pub idx: usize,
+    pub ticks: u64,
+    pub event: midly::TrackEvent<'a>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlayerEvent {
+    pub time: usize,
+    pub note: u8,
+    pub velocity: u8,
+    pub channel: u8,
+    pub tempo: usize,    // µs per beat
+    pub duration: usize, // µs
+    // Not passed to the synthesizer, as rustysynth's note_off doesn't take a velocity
+    pub release_velocity: u8,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PlayerTrack {
+    pub name: Option<String>,
+    pub length: usize,
+    pub events: Vec<PlayerEvent>,
+    pub bank: Option<u16>, // bank selected before the first note (MSB, or MSB * 128 + LSB)
+    pub controls: Vec<PlayerControl>,
+    pub expressions: Vec<PlayerExpression>,
+    pub lyrics: Vec<(usize, String)>, // time (µs), syllable
+}
+
+/// A change of channel volume (CC7), pan (CC10) or expression (CC11).
+#[derive(Clone, Debug)]
+pub struct PlayerControl {
+    pub time: usize,
+    pub channel: u8,
+    pub controller: u8,
+    pub value: u8,
+}
+
+/// A pitch bend or channel pressure change, which MPE instruments apply to the notes of its
+/// channel only.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayerExpression {
+    pub time: usize,
+    pub channel: u8,
+    pub kind: Expression,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Expression {
+    Bend(i32), // 0 .. 16383, centered at 8192
+    Pressure(u8),
+}
+
+/// Tempo and meter changes collected while sequencing, used to map ticks to real-time.
+#[derive(Clone, Debug, Default)]
+pub struct TimeMap {
+    pub ticks_per_beat: usize,
+    pub tempos: Vec<(u64, f64, usize)>, // ticks, time (µs), tempo (µs per beat)
+    pub meters: Vec<(u64, u8, u8)>,     // ticks, numerator, denominator (power of 2)
+    pub end_ticks: u64,
+    pub interpolate: bool, // tempo changes linearly between tempo events instead of stepwise
+    pub smpte_offset: Option<usize>, // µs, the position of the start of the file on the timeline
+    pub tempo_conflicts: usize, // different tempo changes at the same time on different tracks
+}
+
+impl TimeMap {
+    /// Returns the tempo change at or before the given ticks, along with the change of tempo
+    /// (µs per beat) per tick until the next tempo event when interpolating.
+    pub fn segment(&self, ticks: u64) -> ((u64, f64, usize), f64) {
+        let Some(i) = self.tempos.iter().rposition(|t| t.0 <= ticks) else {
+            return ((0, 0.0, 500_000), 0.0);
+        };
+        let t = self.tempos[i];
+        let slope = match self.tempos.get(i + 1) {
+            Some(n) if self.interpolate => (n.2 as f64 - t.2 as f64) / (n.0 - t.0) as f64,
+            _ => 0.0,
+        };
+        (t, slope)
+    }
+
+    pub fn time_at(&self, ticks: u64) -> usize {
+        let ((t_ticks, t_time, tempo), slope) = self.segment(ticks);
+        let dt = (ticks - t_ticks) as f64;
+        let delta = dt * (tempo as f64 + slope * dt / 2.0) / self.ticks_per_beat as f64;
+        (t_time + delta).round() as usize
+    }
+
+    pub fn tempo_at(&self, ticks: u64) -> usize {
+        let ((t_ticks, _, tempo), slope) = self.segment(ticks);
+        (tempo as f64 + slope * (ticks - t_ticks) as f64).round() as usize
+    }
+
+    /// Returns the time of the start of a bar (1-based), if it's within the song.
+    pub fn bar_start(&self, bar: u32) -> Option<usize> {
+        self.beats()
+            .into_iter()
+            .filter(|&(_, downbeat)| downbeat)
+            .nth(bar as usize - 1)
+            .map(|(time, _)| time)
+    }
+
+    /// Returns the time of every beat along with whether it is a downbeat.
+    pub fn beats(&self) -> Vec<(usize, bool)> {
+        let mut beats = Vec::new();
+        let mut meters = self.meters.iter().peekable();
+        let (mut num, mut denom) = (4_u32, 2_u8);
+        let mut beat = 0;
+        let mut ticks: u64 = 0;
+
+        while ticks <= self.end_ticks {
+            // Meter changes restart the bar
+            while let Some(m) = meters.next_if(|m| m.0 <= ticks) {
+                (num, denom) = (u32::from(m.1.max(1)), m.2);
+                beat = 0;
+            }
+            beats.push((self.time_at(ticks), beat == 0));
+
+            let beat_ticks = ((self.ticks_per_beat * 4) >> denom).max(1) as u64;
+            ticks = ticks.saturating_add(beat_ticks);
+            beat = (beat + 1) % num;
+
+            // Don't skip past a meter change that falls between beats
+            if let Some(m) = meters.peek()
+                && m.0 < ticks
+            {
+                ticks = m.0;
+            }
+        }
+
+        beats
+    }
+}
+
+impl PlayerTrack {
+    /// Splits the track into one track per MIDI channel used.
+    pub fn split_by_channel(&self) -> Vec<(u8, PlayerTrack)> {
+        let mut channels: Vec<u8> = self.events.iter().map(|e| e.channel).collect();
+        channels.sort_unstable();
+        channels.dedup();
+
+        channels
+            .into_iter()
+            .map(|ch| {
+                let track = PlayerTrack {
+                    events: self
+                        .events
+                        .iter()
+                        .filter(|e| e.channel == ch)
+                        .cloned()
+                        .collect(),
+                    controls: self
+                        .controls
+                        .iter()
+                        .filter(|c| c.channel == ch)
+                        .cloned()
+                        .collect(),
+                    expressions: self
+                        .expressions
+                        .iter()
+                        .filter(|x| x.channel == ch)
+                        .copied()
+                        .collect(),
+                    ..self.clone()
+                };
+                (ch, track)
+            })
+            .collect()
+    }
+
+    /// Drops the notes starting within any of the ranges (µs), and releases the notes still
+    /// sounding at the start of a range there.
+    pub fn mute(&mut self, ranges: &[(usize, usize)]) {
+        self.events.retain(|e| {
+            !ranges
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&e.time))
+        });
+        for e in self.events.iter_mut() {
+            for &(start, _) in ranges {
+                if e.time < start && e.time + e.duration > start {
+                    e.duration = start - e.time;
+                }
+            }
+        }
+    }
+
+    /// Returns the pan (-1 .. 1) set by CC10 on the channel of the first note, up to that note.
+    pub fn initial_pan(&self) -> Option<f32> {
+        let first = self.events.first()?;
+        let value = self
+            .controls
+            .iter()
+            .rfind(|c| c.controller == 10 && c.channel == first.channel && c.time <= first.time)?
+            .value;
+        Some(((f32::from(value) - 64.0) / 63.0).max(-1.0))
+    }
+}
+
+/// Decodes the text of a meta event. Texts that aren't UTF-8 are most likely Latin-1, which
+/// maps onto Unicode.
+pub fn meta_text(bytes: &[u8]) -> String {
+    String::from_utf8(bytes.to_vec())
+        .unwrap_or_else(|_| bytes.iter().copied().map(char::from).collect())
+}
+
+pub struct Sequencer<'a> {
+    pub tracks: Vec<SequencedTrack<'a>>,
+    pub timemap: TimeMap,
+}
+
+impl<'a> Sequencer<'a> {
+    fn next(&mut self) -> Option<SequencerEvent<'a>> {
+        let (idx, ticks) = self
+            .tracks
+            .iter()
+            .map(|t| t.ticks)
+            .enumerate()
+            .min_by_key(|v| v.1)?;
+
+        if ticks != u64::MAX {
+            let track = &mut self.tracks[idx];
+
+            let event = track.next.unwrap();
+            track.advance();
+
+            Some(SequencerEvent { idx, ticks, event })
+        } else {
+            None
+        }
+    }
+
+    /// Returns all tempo changes (ticks, µs per beat) of the tracks that haven't been played yet.
+    /// Of simultaneous changes, the last one of the lowest track is kept, as when playing.
+    pub fn tempo_changes(&self) -> Vec<(u64, usize)> {
+        let mut changes: Vec<_> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, t)| {
+                let mut ticks = t.ticks;
+                t.next
+                    .into_iter()
+                    .chain(t.track.clone())
+                    .enumerate()
+                    .filter_map(move |(i, e)| {
+                        if i > 0 {
+                            ticks = ticks.saturating_add(e.delta.as_int().into());
+                        }
+                        match e.kind {
+                            midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(t)) => {
+                                Some((ticks, idx, t.as_int() as usize))
+                            }
+                            _ => None,
+                        }
+                    })
+            })
+            .collect();
+        changes.sort_by_key(|c| (c.0, c.1));
+        // The stable sort keeps the order within each track, so a later change of the same
+        // track replaces the kept one, while those of higher tracks are dropped
+        changes.dedup_by(|later, kept| {
+            if later.0 == kept.0 && later.1 == kept.1 {
+                kept.2 = later.2;
+            }
+            later.0 == kept.0
+        });
+        changes
+            .into_iter()
+            .map(|(ticks, _, tempo)| (ticks, tempo))
+            .collect()
+    }
+
+    pub fn play_all(
+        &mut self,
+        timing: midly::Timing,
+        interpolate: bool,
+        progress: &dyn Progress,
+    ) -> Result<Vec<PlayerTrack>, String> {
+        let mut tracks: Vec<_> = std::iter::repeat_with(PlayerTrack::default)
+            .take(self.tracks.len())
+            .collect();
+
+        let ticks_per_beat = match timing {
+            midly::Timing::Metrical(tpb) => usize::from(tpb.as_int()),
+            midly::Timing::Timecode(..) => return Err("SMPTE timing not supported".into()),
+        };
+        let mut tempo: usize = 500_000;
+        // The ticks and track of the last tempo change, as simultaneous tempo changes on
+        // different tracks are resolved in favor of the lowest track, usually the conductor
+        let mut tempo_source: Option<(u64, usize)> = None;
+
+        // Real-time is accumulated in fractional microseconds to avoid drift on long files
+        let mut base_time: f64 = 0.0;
+        let mut base_ticks: u64 = 0;
+
+        self.timemap = TimeMap {
+            ticks_per_beat,
+            tempos: vec![(0, 0.0, tempo)],
+            ..Default::default()
+        };
+
+        // Interpolating needs to know the next tempo ahead of time, so map all tempos up front
+        if interpolate {
+            self.timemap.interpolate = true;
+            for (ticks, tempo) in self.tempo_changes() {
+                let &(p_ticks, p_time, p_tempo) = self.timemap.tempos.last().unwrap();
+                let average = (p_tempo + tempo) as f64 / 2.0;
+                let time = p_time + (ticks - p_ticks) as f64 * average / ticks_per_beat as f64;
+                self.timemap.tempos.push((ticks, time, tempo));
+            }
+        }
+
+        // Bank select MSB (CC0) and LSB (CC32) per track, applied at its first note
+        let mut banks: Vec<(Option<u8>, u8)> = vec![(None, 0); self.tracks.len()];
+
+        // Currently sounding notes per track, by (channel, key), as index into its events
+        let mut sounding: Vec<std::collections::HashMap<(u8, u8), usize>> =
+            vec![Default::default(); self.tracks.len()];
+
+        let total = self.tracks.iter().map(|t| t.count).sum::<usize>() as u64;
+        let mut done = 0;
+        progress.update(Stage::Sequencing, done, total);
+
+        while let Some(e) = self.next() {
+            // next() always yields the earliest pending event and each track's ticks only grow,
+            // so ticks never decrease across the merged stream, even for simultaneous events
+            debug_assert!(e.ticks >= base_ticks, "events out of order");
+            // Should this ever fail, treat the event as simultaneous rather than hours later
+            let delta_ticks = e.ticks.saturating_sub(base_ticks);
+            let delta_time = delta_ticks as f64 * tempo as f64 / ticks_per_beat as f64;
+
+            let (time, event_tempo) = if interpolate {
+                (
+                    self.timemap.time_at(e.ticks),
+                    self.timemap.tempo_at(e.ticks),
+                )
+            } else {
+                ((base_time + delta_time).round() as usize, tempo)
+            };
+
+            match e.event.kind {
+                // next() plays the tracks in order at the same ticks, so a lower track is first
+                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(t))
+                    if tempo_source.is_some_and(|(ticks, idx)| ticks == e.ticks && idx < e.idx) =>
+                {
+                    self.timemap.tempo_conflicts += usize::from(t.as_int() as usize != tempo);
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(t)) => {
+                    tempo = t.as_int() as usize;
+                    tempo_source = Some((e.ticks, e.idx));
+
+                    base_time += delta_time;
+                    base_ticks += delta_ticks;
+
+                    if !interpolate {
+                        self.timemap.tempos.push((base_ticks, base_time, tempo));
+                    }
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(n, d, _, _)) => {
+                    self.timemap.meters.push((e.ticks, n, d));
+                }
+                // Only the offset at the start of the file is meaningful
+                midly::TrackEventKind::Meta(midly::MetaMessage::SmpteOffset(smpte))
+                    if e.ticks == 0 && self.timemap.smpte_offset.is_none() =>
+                {
+                    let minutes = u32::from(smpte.hour()) * 60 + u32::from(smpte.minute());
+                    let secs = f64::from(minutes * 60) + f64::from(smpte.second_f32());
+                    self.timemap.smpte_offset = Some((secs * 1_000_000.0).round() as usize);
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(n)) => {
+                    tracks[e.idx].name = Some(meta_text(n));
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::Lyric(l)) => {
+                    tracks[e.idx].lyrics.push((time, meta_text(l)));
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) => {
+                    tracks[e.idx].length = time;
+
+                    // Notes still held at the end of the track are released there
+                    for (_, i) in sounding[e.idx].drain() {
+                        let ev = &mut tracks[e.idx].events[i];
+                        ev.duration = time - ev.time;
+                    }
+                }
+                midly::TrackEventKind::Midi {
+                    channel: c,
+                    message: midly::MidiMessage::NoteOn { key: k, vel: v },
+                } if v > 0 => {
+                    if tracks[e.idx].events.is_empty()
+                        && let (Some(msb), lsb) = banks[e.idx]
+                    {
+                        // GM2/GS/XG variation banks are addressed by the LSB
+                        tracks[e.idx].bank = Some(match lsb {
+                            0 => msb.into(),
+                            lsb => u16::from(msb) * 128 + u16::from(lsb),
+                        });
+                    }
+
+                    let events = &mut tracks[e.idx].events;
+
+                    // A repeated note-on before the note-off retriggers the note
+                    if let Some(i) = sounding[e.idx].insert((c.into(), k.into()), events.len()) {
+                        events[i].duration = time - events[i].time;
+                    }
+
+                    events.push(PlayerEvent {
+                        time,
+                        note: k.into(),
+                        velocity: v.into(),
+                        channel: c.into(),
+                        tempo: event_tempo,
+                        duration: 0,
+                        release_velocity: 64,
+                    });
+                }
+                midly::TrackEventKind::Midi {
+                    channel: c,
+                    message: midly::MidiMessage::NoteOff { key: k, vel: v },
+                } => {
+                    // Note-offs without a matching note-on are ignored
+                    if let Some(i) = sounding[e.idx].remove(&(c.into(), k.into())) {
+                        let ev = &mut tracks[e.idx].events[i];
+                        ev.duration = time - ev.time;
+                        ev.release_velocity = v.into();
+                    }
+                }
+                midly::TrackEventKind::Midi {
+                    channel: c,
+                    message: midly::MidiMessage::NoteOn { key: k, .. },
+                } => {
+                    // A note-on with zero velocity is a note-off with the default release velocity
+                    if let Some(i) = sounding[e.idx].remove(&(c.into(), k.into())) {
+                        let ev = &mut tracks[e.idx].events[i];
+                        ev.duration = time - ev.time;
+                    }
+                }
+                midly::TrackEventKind::Midi {
+                    message:
+                        midly::MidiMessage::Controller {
+                            controller: c,
+                            value: v,
+                        },
+                    ..
+                } if c == 0 || c == 32 => {
+                    if c == 0 {
+                        banks[e.idx].0 = Some(v.into());
+                    } else {
+                        banks[e.idx].1 = v.into();
+                    }
+                }
+                midly::TrackEventKind::Midi {
+                    channel: ch,
+                    message:
+                        midly::MidiMessage::Controller {
+                            controller: c,
+                            value: v,
+                        },
+                } if c == 7 || c == 10 || c == 11 => {
+                    tracks[e.idx].controls.push(PlayerControl {
+                        time,
+                        channel: ch.into(),
+                        controller: c.into(),
+                        value: v.into(),
+                    });
+                }
+                midly::TrackEventKind::Midi {
+                    channel: ch,
+                    message: midly::MidiMessage::PitchBend { bend },
+                } => {
+                    tracks[e.idx].expressions.push(PlayerExpression {
+                        time,
+                        channel: ch.into(),
+                        kind: Expression::Bend(bend.0.as_int().into()),
+                    });
+                }
+                midly::TrackEventKind::Midi {
+                    channel: ch,
+                    message: midly::MidiMessage::ChannelAftertouch { vel },
+                } => {
+                    tracks[e.idx].expressions.push(PlayerExpression {
+                        time,
+                        channel: ch.into(),
+                        kind: Expression::Pressure(vel.into()),
+                    });
+                }
+                _ => { /* println!("skipping: {:?}", e); */ }
+            };
+            self.timemap.end_ticks = e.ticks;
+            done += 1;
+            progress.update(Stage::Sequencing, done, total);
+        }
+
+        Ok(tracks)
+    }
+}
+
+/// A section of the song, starting at a bar, whose tempo and/or meter replace the MIDI file's.
+/// A section without a tempo or meter hands it back to the file.
+#[derive(Clone, Debug)]
+pub struct TempoSection {
+    pub bar: u32,             // 1-based
+    pub tempo: Option<usize>, // µs per beat
+    pub meter: Option<(u8, u8)>,
+}
+
+impl TempoSection {
+    pub fn from_toml(config: &toml::Table) -> Result<Vec<Self>, String> {
+        let Some(sections) = config.get("tempo_override") else {
+            return Ok(Vec::new());
+        };
+        let sections = sections
+            .as_array()
+            .ok_or("tempo_override must be an array of tables")?;
+
+        let mut result: Vec<Self> = Vec::new();
+        for section in sections {
+            let bar = match section.get("bar").and_then(|v| v.as_integer()) {
+                Some(bar) if bar >= 1 && bar <= i64::from(u32::MAX) => bar as u32,
+                _ => return Err("tempo_override bar must be a bar number from 1".into()),
+            };
+            if result.last().is_some_and(|s| s.bar >= bar) {
+                return Err("tempo_override bars must be in increasing order".into());
+            }
+            let tempo = match section.get("bpm").map(toml_float) {
+                None => None,
+                Some(Some(bpm)) if (1.0..=1000.0).contains(&bpm) => {
+                    Some((60_000_000.0 / bpm).round() as usize)
+                }
+                Some(_) => return Err("tempo_override bpm must be between 1 and 1000".into()),
+            };
+            let meter = match section.get("meter").map(|v| v.as_str()) {
+                None => None,
+                Some(Some(meter)) => Some(parse_meter(meter).ok_or_else(|| {
+                    format!(
+                        "tempo_override meter {} is not a time signature like 3/4",
+                        meter
+                    )
+                })?),
+                Some(None) => return Err("tempo_override meter must be a string".into()),
+            };
+            result.push(Self { bar, tempo, meter });
+        }
+        Ok(result)
+    }
+}
+
+/// Parses a time signature such as "6/8" into its numerator and denominator (power of 2).
+pub fn parse_meter(s: &str) -> Option<(u8, u8)> {
+    let (num, denom) = s.split_once('/')?;
+    let num: u8 = num.trim().parse().ok().filter(|&n| n > 0)?;
+    let denom: u8 = denom
+        .trim()
+        .parse()
+        .ok()
+        .filter(|d: &u8| d.is_power_of_two())?;
+    Some((num, denom.trailing_zeros() as u8))
+}
+
+/// Replaces the tempo and meter changes of the MIDI file within the overridden sections. The
+/// file's changes in those sections are dropped, and the section's own are added to the first
+/// track, as is the file's tempo or meter where a section hands it back.
+pub fn apply_tempo_sections(tracks: &mut [midly::Track], sections: &[TempoSection], tpb: u32) {
+    if sections.is_empty() || tracks.is_empty() {
+        return;
+    }
+
+    // Convert all tracks to absolute ticks
+    let mut events: Vec<Vec<(u64, midly::TrackEventKind)>> = tracks
+        .iter()
+        .map(|track| {
+            let mut ticks = 0_u64;
+            track
+                .iter()
+                .map(|e| {
+                    ticks = ticks.saturating_add(e.delta.as_int().into());
+                    (ticks, e.kind)
+                })
+                .collect()
+        })
+        .collect();
+    let song_end = events
+        .iter()
+        .filter_map(|t| t.last().map(|e| e.0))
+        .max()
+        .unwrap_or(0);
+
+    let mut file_tempos = vec![(0, 500_000)];
+    let mut file_meters = vec![(0, (4, 2))];
+    let mut changes: Vec<_> = events.iter().flatten().collect();
+    changes.sort_by_key(|e| e.0);
+    for &(ticks, kind) in changes {
+        match kind {
+            midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(t)) => {
+                file_tempos.push((ticks, t.as_int() as usize))
+            }
+            midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(n, d, _, _)) => {
+                file_meters.push((ticks, (n, d)))
+            }
+            _ => {}
+        }
+    }
+    fn at<T: Copy>(changes: &[(u64, T)], ticks: u64) -> T {
+        changes.iter().rfind(|c| c.0 <= ticks).unwrap().1
+    }
+
+    // Find the start of each section by counting bars in the meter in effect
+    let mut starts = Vec::new();
+    let (mut bar, mut ticks) = (1, 0_u64);
+    let mut current: Option<&TempoSection> = None;
+    for section in sections {
+        while bar < section.bar && ticks < song_end {
+            let (num, denom) = current
+                .and_then(|c| c.meter)
+                .unwrap_or_else(|| at(&file_meters, ticks));
+            ticks = ticks.saturating_add(u64::from(
+                u32::from(num.max(1)) * ((tpb * 4) >> denom).max(1),
+            ));
+            bar += 1;
+        }
+        if ticks >= song_end {
+            break;
+        }
+        starts.push(ticks);
+        current = Some(section);
+    }
+    let sections = &sections[..starts.len()];
+    let section_at = |ticks: u64| {
+        starts
+            .iter()
+            .rposition(|&s| s <= ticks)
+            .map(|i| &sections[i])
+    };
+
+    // Drop the file's changes within the overridden sections
+    for track in events.iter_mut() {
+        track.retain(|&(ticks, kind)| match kind {
+            midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(_)) => {
+                section_at(ticks).is_none_or(|s| s.tempo.is_none())
+            }
+            midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(..)) => {
+                section_at(ticks).is_none_or(|s| s.meter.is_none())
+            }
+            _ => true,
+        });
+    }
+
+    // Add the changes of the sections, or the file's where the previous section overrode it
+    let mut injected = Vec::new();
+    let mut previous: Option<&TempoSection> = None;
+    for (section, &start) in sections.iter().zip(starts.iter()) {
+        let overrode_tempo = previous.is_some_and(|p| p.tempo.is_some());
+        if let Some(tempo) = section
+            .tempo
+            .or(overrode_tempo.then(|| at(&file_tempos, start)))
+        {
+            let tempo = midly::num::u24::new(tempo.min(0xff_ffff) as u32);
+            injected.push((
+                start,
+                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo)),
+            ));
+        }
+        let overrode_meter = previous.is_some_and(|p| p.meter.is_some());
+        if let Some((n, d)) = section
+            .meter
+            .or(overrode_meter.then(|| at(&file_meters, start)))
+        {
+            let meter = midly::MetaMessage::TimeSignature(n, d, 24, 8);
+            injected.push((start, midly::TrackEventKind::Meta(meter)));
+        }
+        previous = Some(section);
+    }
+
+    // The end of the first track stays last, and may need to move for the added changes
+    let first = &mut events[0];
+    let end = match first.last() {
+        Some(&(ticks, midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack))) => {
+            first.pop();
+            Some(ticks)
+        }
+        _ => None,
+    };
+    first.extend(injected);
+    first.sort_by_key(|e| e.0);
+    if let Some(ticks) = end {
+        let last = first.last().map_or(0, |e| e.0);
+        first.push((
+            ticks.max(last),
+            midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        ));
+    }
+
+    // Convert back to delta ticks
+    for (track, events) in tracks.iter_mut().zip(events) {
+        let mut previous = 0;
+        *track = events
+            .into_iter()
+            .map(|(ticks, kind)| {
+                // Deltas are 28-bit, which only a moved end of track could exceed
+                let delta = (ticks - previous).min(0x0fff_ffff) as u32;
+                previous = ticks;
+                midly::TrackEvent {
+                    delta: delta.into(),
+                    kind,
+                }
+            })
+            .collect();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InstrumentSetting {
+    pub bank: u16,
+    pub preset: u8,
+    pub transpose: Option<i8>,
+    pub pan: Option<f32>,     // -1 .. 1
+    pub balance: Option<f32>, // -1 .. 1
+    pub gain: Option<f32>,    // dB
+    pub max_len_ms: Option<usize>,
+    pub delay_beats: Option<f32>,
+    pub delay_feedback: Option<f32>, // 0 .. 1
+    pub delay_mix: Option<f32>,      // 0 .. 1
+    pub reverb_send: Option<f32>,    // 0 .. 1
+    pub gate_db: Option<f32>,        // dBFS
+    pub offset_ms: Option<f32>,      // positive = later
+    pub per_channel: bool,
+    pub vel_trim: Option<f32>,
+    pub invert: bool,
+    pub attack: Option<f32>,  // ms
+    pub decay: Option<f32>,   // ms
+    pub sustain: Option<f32>, // 0 .. 1
+    pub release: Option<f32>, // ms
+    pub width: Option<f32>,   // 0 = mono, 1 = normal
+    pub strum_ms: Option<f32>,
+    pub strum_down: bool,
+    pub min_note_ms: Option<f32>,
+    pub cc_interp: Option<CcInterp>,
+    pub bus: Option<String>,
+    pub release_tail_ms: Option<f32>,
+    pub max_voices: Option<usize>,
+    pub voice_steal: VoiceSteal,
+    pub preset_name: Option<String>,
+    pub fixed_velocity: Option<u8>,
+    pub output: usize,      // channel pair of the output, 0 = channels 1 and 2
+    pub level: Option<f32>, // dBFS
+    pub mute: Vec<(usize, usize)>, // µs, end exclusive
+    pub mute_bars: Vec<(u32, u32)>, // 1-based, inclusive, moved to mute by apply_mutes
+    pub mpe: Option<u8>,    // pitch bend range (semitones) of the notes' channels
+    pub xfade: Option<(u8, u8)>, // velocities
+    pub xfade_up: bool,     // the louder layer of the crossfade, set by plan_renders
+}
+
+/// The note released to make room for a new one beyond `max_voices`: the oldest, or the one of
+/// the lowest velocity (the oldest of those, if several).
+#[derive(Clone, Copy, Debug)]
+pub enum VoiceSteal {
+    Oldest,
+    Quietest,
+}
+
+/// Names the type of a configuration value, with its article, for error messages.
+pub fn toml_type(v: &toml::Value) -> String {
+    match v.type_str() {
+        t @ ("integer" | "array") => format!("an {}", t),
+        t => format!("a {}", t),
+    }
+}
+
+/// Returns a table of the configuration, if present. Values of another type are an error
+/// rather than missing, so that the mistake can be reported.
+pub fn toml_table<'a>(
+    config: &'a toml::Table,
+    key: &str,
+) -> Result<Option<&'a toml::Table>, String> {
+    match config.get(key) {
+        None => Ok(None),
+        Some(toml::Value::Table(table)) => Ok(Some(table)),
+        Some(v) => Err(format!("{} must be a table, not {}", key, toml_type(v))),
+    }
+}
+
+/// Returns the list of instrument settings of a track or channel.
+pub fn instrument_list<'a>(name: &str, v: &'a toml::Value) -> Result<&'a [toml::Value], String> {
+    v.as_array().map(|a| &a[..]).ok_or_else(|| {
+        format!(
+            "Instruments of {} must be an array of tables (written as [[...]]), not {}",
+            name,
+            toml_type(v)
+        )
+    })
+}
+
+pub fn toml_float(v: &toml::Value) -> Option<f32> {
+    match v {
+        toml::Value::Integer(i) => Some(*i as f32),
+        toml::Value::Float(f) => Some(*f as f32),
+        _ => None,
+    }
+}
+
+/// Returns a number of a setting, which may be written as an integer or a float.
+pub fn setting_float(setting: &toml::Value, key: &str) -> Result<Option<f32>, String> {
+    setting
+        .get(key)
+        .map(|v| {
+            toml_float(v).ok_or_else(|| format!("{} must be a number, not {}", key, toml_type(v)))
+        })
+        .transpose()
+}
+
+pub fn setting_int(setting: &toml::Value, key: &str) -> Result<Option<i64>, String> {
+    setting
+        .get(key)
+        .map(|v| {
+            v.as_integer()
+                .ok_or_else(|| format!("{} must be an integer, not {}", key, toml_type(v)))
+        })
+        .transpose()
+}
+
+pub fn setting_str(setting: &toml::Value, key: &str) -> Result<Option<String>, String> {
+    setting
+        .get(key)
+        .map(|v| {
+            v.as_str()
+                .map(String::from)
+                .ok_or_else(|| format!("{} must be a string, not {}", key, toml_type(v)))
+        })
+        .transpose()
+}
+
+/// Returns a flag of a setting, which is off unless set.
+pub fn setting_flag(setting: &toml::Value, key: &str) -> Result<bool, String> {
+    match setting.get(key) {
+        None => Ok(false),
+        Some(toml::Value::Boolean(flag)) => Ok(*flag),
+        Some(v) => Err(format!(
+            "{} must be true or false, not {}",
+            key,
+            toml_type(v)
+        )),
+    }
+}
+
+/// Parses the `output` channel pair of an instrument or bus, numbered from 1, into an index.
+pub fn output_pair(v: Option<&toml::Value>) -> Result<usize, String> {
+    match v.map(|v| v.as_integer()) {
+        None => Ok(0),
+        Some(Some(pair @ 1..=4)) => Ok(pair as usize - 1),
+        Some(_) => Err("output must be a channel pair from 1 to 4".into()),
+    }
+}
+
+/// Parses a list of `[start, end]` ranges of values.
+pub fn toml_ranges<T>(
+    v: &toml::Value,
+    value: impl Fn(&toml::Value) -> Option<T>,
+) -> Option<Vec<(T, T)>> {
+    v.as_array()?
+        .iter()
+        .map(|r| match r.as_array()?.as_slice() {
+            [start, end] => Some((value(start)?, value(end)?)),
+            _ => None,
+        })
+        .collect()
+}
+
+impl InstrumentSetting {
+    pub fn from_toml(setting: &toml::Value) -> Result<Self, String> {
+        if !setting.is_table() {
+            return Err(format!(
+                "Instrument settings must be tables, not {}",
+                toml_type(setting)
+            ));
+        }
+        if setting
+            .get("tracks")
+            .is_some_and(|v| !v.as_array().is_some_and(|a| a.iter().all(|t| t.is_str())))
+        {
+            return Err("tracks must be a list of track names".into());
+        }
+        if setting.get("pan").is_some() && setting.get("balance").is_some() {
+            return Err("pan and balance can't both be set".into());
+        }
+        if setting.get("output").is_some() && setting.get("bus").is_some() {
+            return Err("output and bus can't both be set, route the bus instead".into());
+        }
+        if setting
+            .get("mpe")
+            .is_some_and(|v| v.as_bool() == Some(true))
+            && setting
+                .get("per_channel")
+                .is_some_and(|v| v.as_bool() == Some(true))
+        {
+            return Err("mpe and per_channel can't both be set".into());
+        }
+
+        Ok(Self {
+            bank: match (
+                setting_int(setting, "bank")?,
+                setting_int(setting, "bank_lsb")?,
+            ) {
+                (Some(msb @ 0..=127), Some(lsb @ 0..=127)) => (msb * 128 + lsb) as u16,
+                (_, Some(_)) => return Err("Invalid bank_lsb value".into()),
+                (Some(bank @ 0..=16383), None) => bank as u16,
+                (Some(_), None) => return Err("Invalid bank value".into()),
+                (None, None) => 0,
+            },
+
+            preset: match (setting.get("preset"), setting.get("preset_name")) {
+                (Some(_), Some(_)) => return Err("preset and preset_name can't both be set".into()),
+                // Looked up in the soundfont by resolve_preset_name
+                (None, Some(_)) => 0,
+                (None, None) => return Err("Missing preset value".into()),
+                (Some(_), None) => match setting_int(setting, "preset")? {
+                    Some(preset @ 0..=127) => preset as u8,
+                    _ => return Err("preset must be from 0 to 127".into()),
+                },
+            },
+
+            fixed_velocity: match setting.get("fixed_velocity").map(|v| v.as_integer()) {
+                None => None,
+                Some(Some(v @ 1..=127)) => Some(v as u8),
+                Some(_) => return Err("fixed_velocity must be between 1 and 127".into()),
+            },
+
+            preset_name: setting_str(setting, "preset_name")?,
+
+            transpose: match setting_int(setting, "tsp")? {
+                None => None,
+                Some(semitones @ -127..=127) => Some(semitones as i8),
+                Some(_) => return Err("tsp must be from -127 to 127 semitones".into()),
+            },
+
+            pan: setting_float(setting, "pan")?,
+
+            balance: setting_float(setting, "balance")?,
+
+            gain: setting_float(setting, "gain")?,
+
+            max_len_ms: match setting_int(setting, "max_len_ms")? {
+                None => None,
+                Some(ms @ 0..) => Some(ms as usize),
+                Some(_) => return Err("max_len_ms must not be negative".into()),
+            },
+
+            delay_beats: setting_float(setting, "delay_beats")?,
+
+            delay_feedback: setting_float(setting, "delay_feedback")?,
+
+            delay_mix: setting_float(setting, "delay_mix")?,
+
+            reverb_send: setting_float(setting, "reverb_send")?,
+
+            gate_db: setting_float(setting, "gate_db")?,
+
+            offset_ms: setting_float(setting, "offset_ms")?,
+
+            per_channel: setting_flag(setting, "per_channel")?,
+
+            vel_trim: setting_float(setting, "vel_trim")?,
+
+            invert: setting_flag(setting, "invert")?,
+
+            attack: setting_float(setting, "attack")?,
+
+            decay: setting_float(setting, "decay")?,
+
+            sustain: setting_float(setting, "sustain")?,
+
+            release: setting_float(setting, "release")?,
+
+            width: setting_float(setting, "width")?,
+
+            strum_ms: setting_float(setting, "strum_ms")?,
+
+            min_note_ms: setting_float(setting, "min_note_ms")?,
+
+            cc_interp: CcInterp::from_toml(setting.get("cc_interp"))?,
+
+            bus: setting_str(setting, "bus")?,
+
+            release_tail_ms: setting_float(setting, "release_tail_ms")?,
+
+            level: setting_float(setting, "level")?,
+
+            output: output_pair(setting.get("output"))?,
+
+            mute: match setting.get("mute") {
+                None => Vec::new(),
+                Some(v) => toml_ranges(v, toml_float)
+                    .filter(|r| r.iter().all(|&(start, end)| 0.0 <= start && start < end))
+                    .ok_or("mute must be a list of [start, end] ranges in seconds")?
+                    .into_iter()
+                    .map(|(start, end)| ((start * 1e6) as usize, (end * 1e6) as usize))
+                    .collect(),
+            },
+
+            mute_bars: match setting.get("mute_bars") {
+                None => Vec::new(),
+                Some(v) => toml_ranges(v, |b| b.as_integer())
+                    .filter(|r| {
+                        r.iter().all(|&(first, last)| {
+                            1 <= first && first <= last && last < u32::MAX.into()
+                        })
+                    })
+                    .ok_or("mute_bars must be a list of [first, last] bar ranges from 1")?
+                    .into_iter()
+                    .map(|(first, last)| (first as u32, last as u32))
+                    .collect(),
+            },
+
+            max_voices: match setting.get("max_voices").map(|v| v.as_integer()) {
+                None => None,
+                Some(Some(n)) if n >= 1 => Some(n as usize),
+                Some(_) => return Err("max_voices must be at least 1".into()),
+            },
+
+            voice_steal: match (
+                setting.get("voice_steal").map(|v| v.as_str()),
+                setting.get("max_voices"),
+            ) {
+                (None, _) => VoiceSteal::Oldest,
+                (Some(_), None) => return Err("voice_steal needs max_voices".into()),
+                (Some(Some("oldest")), _) => VoiceSteal::Oldest,
+                (Some(Some("quietest")), _) => VoiceSteal::Quietest,
+                (Some(_), _) => return Err("voice_steal must be one of oldest, quietest".into()),
+            },
+
+            strum_down: match setting.get("strum_dir").map(|v| v.as_str()) {
+                None | Some(Some("up")) => false,
+                Some(Some("down")) => true,
+                Some(_) => return Err("strum_dir must be one of up, down".into()),
+            },
+
+            // MPE controllers bend their notes by 48 semitones unless configured otherwise
+            mpe: match (
+                setting.get("mpe").map(|v| v.as_bool()),
+                setting.get("mpe_bend_range").map(|v| v.as_integer()),
+            ) {
+                (Some(None), _) => return Err("mpe must be true or false".into()),
+                (Some(Some(true)), None) => Some(48),
+                (Some(Some(true)), Some(Some(range @ 1..=96))) => Some(range as u8),
+                (Some(Some(true)), Some(_)) => {
+                    return Err("mpe_bend_range must be from 1 to 96 semitones".into());
+                }
+                (_, Some(_)) => return Err("mpe_bend_range needs mpe = true".into()),
+                (None | Some(Some(false)), None) => None,
+            },
+
+            xfade: match (
+                setting.get("xfade_lo").map(|v| v.as_integer()),
+                setting.get("xfade_hi").map(|v| v.as_integer()),
+            ) {
+                (None, None) => None,
+                (Some(Some(lo @ 1..=127)), Some(Some(hi @ 1..=127))) if lo < hi => {
+                    Some((lo as u8, hi as u8))
+                }
+                _ => {
+                    return Err(
+                        "xfade_lo and xfade_hi must be velocities, xfade_lo below xfade_hi".into(),
+                    );
+                }
+            },
+
+            xfade_up: false,
+        })
+    }
+
+    /// Sets the bank and preset to those of the soundfont preset named by `preset_name`, if
+    /// any. When several presets share the name, a configured bank picks one of them.
+    pub fn resolve_preset_name(
+        &mut self,
+        sf: &rustysynth::SoundFont,
+        bank_configured: bool,
+    ) -> Result<(), String> {
+        let Some(name) = &self.preset_name else {
+            return Ok(());
+        };
+        let matches: Vec<_> = sf
+            .get_presets()
+            .iter()
+            .filter(|p| p.get_name().trim().eq_ignore_ascii_case(name.trim()))
+            .filter(|p| !bank_configured || p.get_bank_number() == i32::from(self.bank))
+            .map(|p| (p.get_bank_number() as u16, p.get_patch_number() as u8))
+            .collect();
+        match matches[..] {
+            [(bank, preset)] => {
+                (self.bank, self.preset) = (bank, preset);
+                Ok(())
+            }
+            [] if bank_configured => Err(format!(
+                "No preset named {} in bank {} of the soundfont",
+                name, self.bank
+            )),
+            [] => Err(format!("No preset named {} in the soundfont", name)),
+            _ => Err(format!(
+                "Presets {} are all named {}, set bank to pick one",
+                matches
+                    .iter()
+                    .map(|(bank, preset)| format!("{}:{}", bank, preset))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                name
+            )),
+        }
+    }
+
+    /// Returns the offset of the track in samples at the given sample rate.
+    pub fn offset(&self, sr: usize) -> isize {
+        (self.offset_ms.unwrap_or(0f32) * sr as f32 / 1_000.0).round() as isize
+    }
+}
+
+/// Renders a short test note with the given preset and checks that it produces sound.
+pub fn preset_is_audible(
+    sf: &std::sync::Arc<rustysynth::SoundFont>,
+    bank: u16,
+    preset: u8,
+) -> bool {
+    let settings = rustysynth::SynthesizerSettings::new(44100);
+    let Ok(mut synth) = rustysynth::Synthesizer::new(sf, &settings) else {
+        return false;
+    };
+
+    synth.process_midi_message(0, 0xb0, 0x00, bank.into());
+    synth.process_midi_message(0, 0xc0, preset.into(), 0);
+    synth.note_on(0, 60, 100);
+
+    // 100ms should be plenty to get past any attack delay
+    let mut left = vec![0_f32; 4410];
+    let mut right = vec![0_f32; 4410];
+    synth.render(&mut left, &mut right);
+
+    left.iter().chain(right.iter()).any(|s| s.abs() > 1e-6)
+}
+
+/// Sample counters shared by all render threads, to report their progress as a whole, either
+/// to a sink or by polling.
+#[derive(Default)]
+pub struct SharedProgress<'a> {
+    pub total: std::sync::atomic::AtomicU64,
+    pub done: std::sync::atomic::AtomicU64,
+    pub sink: Option<&'a (dyn Progress + Sync)>,
+}
+
+/// Adds the progress of one render thread to the shared counters.
+pub struct TrackProgress<'a> {
+    pub shared: std::sync::Arc<SharedProgress<'a>>,
+    pub total: std::sync::atomic::AtomicU64,
+    pub done: std::sync::atomic::AtomicU64,
+}
+
+impl<'a> TrackProgress<'a> {
+    pub fn new(shared: std::sync::Arc<SharedProgress<'a>>) -> Self {
+        Self {
+            shared,
+            total: Default::default(),
+            done: Default::default(),
+        }
+    }
+}
+
+impl Progress for TrackProgress<'_> {
+    fn update(&self, stage: Stage, current: u64, total: u64) {
+        use std::sync::atomic::Ordering::Relaxed;
+        let total_delta = total.saturating_sub(self.total.swap(total, Relaxed));
+        let done_delta = current.saturating_sub(self.done.swap(current, Relaxed));
+        let total = self.shared.total.fetch_add(total_delta, Relaxed) + total_delta;
+        let done = self.shared.done.fetch_add(done_delta, Relaxed) + done_delta;
+        if let Some(sink) = self.shared.sink {
+            sink.update(stage, done, total);
+        }
+    }
+}
+
+/// Synthesis quality presets. RustySynth always uses linear sample interpolation, so the
+/// CPU/quality tradeoff is made through the effects and the number of voices instead: `draft`
+/// disables reverb and chorus and limits polyphony for fast previews, `high` allows more voices
+/// so that dense passages don't steal notes.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum RenderQuality {
+    Draft,
+    #[default]
+    Normal,
+    High,
+}
+
+impl RenderQuality {
+    pub fn from_toml(config: &toml::Table) -> Result<Self, String> {
+        match config.get("quality").map(|v| v.as_str()) {
+            None => Ok(Self::default()),
+            Some(Some("draft")) => Ok(Self::Draft),
+            Some(Some("normal")) => Ok(Self::Normal),
+            Some(Some("high")) => Ok(Self::High),
+            Some(_) => Err("quality must be one of draft, normal, high".into()),
+        }
+    }
+
+    pub fn settings(
+        self,
+        sample_rate: i32,
+        polyphony: Option<usize>,
+    ) -> rustysynth::SynthesizerSettings {
+        let mut settings = rustysynth::SynthesizerSettings::new(sample_rate);
+        match self {
+            Self::Draft => {
+                settings.enable_reverb_and_chorus = false;
+                settings.maximum_polyphony = 32;
+            }
+            Self::Normal => {}
+            Self::High => settings.maximum_polyphony = 256,
+        }
+        if let Some(voices) = polyphony {
+            settings.maximum_polyphony = voices;
+        }
+        settings
+    }
+}
+
+/// How the volume, pan and expression automation of a track is applied between its
+/// controller changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CcInterp {
+    Step,   // exact MIDI behavior
+    Linear, // ramp to the next change
+    Smooth, // follow the steps with a 20ms time constant, avoiding zipper noise
+}
+
+impl CcInterp {
+    pub fn from_toml(value: Option<&toml::Value>) -> Result<Option<Self>, String> {
+        match value.map(|v| v.as_str()) {
+            None => Ok(None),
+            Some(Some("step")) => Ok(Some(Self::Step)),
+            Some(Some("linear")) => Ok(Some(Self::Linear)),
+            Some(Some("smooth")) => Ok(Some(Self::Smooth)),
+            Some(_) => Err("cc_interp must be one of step, linear, smooth".into()),
+        }
+    }
+}
+
+/// Returns the number of voices configured by `polyphony`, overriding that of the quality.
+pub fn polyphony_from_toml(config: &toml::Table) -> Result<Option<usize>, String> {
+    match config.get("polyphony").map(|v| v.as_integer()) {
+        None => Ok(None),
+        Some(Some(voices @ 8..=256)) => Ok(Some(voices as usize)),
+        Some(_) => Err("polyphony must be a number of voices from 8 to 256".into()),
+    }
+}
+
+/// Returns the tuning offset (in semitones) for the concert pitch given by `tuning_hz` (A4).
+pub fn tuning_from_toml(config: &toml::Table) -> Result<f32, String> {
+    match config.get("tuning_hz").map(toml_float) {
+        None => Ok(0.0),
+        Some(Some(hz)) if (390.0..=460.0).contains(&hz) => Ok(12.0 * (hz / 440.0).log2()),
+        Some(_) => Err("tuning_hz must be a frequency between 390 and 460".into()),
+    }
+}
+
+/// A microtonal tuning from a Scala scale (.scl) and keyboard mapping (.kbm), as configured by
+/// `tuning_file` and `keyboard_map`. Without a mapping, consecutive keys play consecutive
+/// degrees, with degree 0 on middle C and A above it at 440 Hz.
+#[derive(Debug)]
+pub struct Scale {
+    pub pitches: Vec<f64>, // cents of degrees 1 ..= N, the last being the period
+    pub map: Vec<Option<i32>>, // degree per key of the mapping, repeating; empty maps linearly
+    pub keys: (u8, u8),    // range of retuned keys, others are silent
+    pub middle: i32,       // key of degree 0
+    pub reference: (i32, f64), // key and its frequency (Hz)
+    pub octave_degree: i32, // degrees from one repetition of the mapping to the next
+}
+
+impl Scale {
+    pub fn from_config(config: &toml::Table) -> Result<Option<Self>, String> {
+        let file = |key: &str| match config.get(key) {
+            None => Ok(None),
+            Some(toml::Value::String(name)) => std::fs::read_to_string(name)
+                .map(|text| Some((name, text)))
+                .map_err(|e| format!("{} {} can't be read: {}", key, name, e)),
+            Some(v) => Err(format!("{} must be a string, not {}", key, toml_type(v))),
+        };
+        let Some((name, scl)) = file("tuning_file")? else {
+            if config.contains_key("keyboard_map") {
+                return Err("keyboard_map needs a tuning_file".into());
+            }
+            return Ok(None);
+        };
+        let mut scale = Self::from_scl(&scl).map_err(|e| format!("tuning_file {}: {}", name, e))?;
+        if let Some((name, kbm)) = file("keyboard_map")? {
+            scale
+                .parse_kbm(&kbm)
+                .map_err(|e| format!("keyboard_map {}: {}", name, e))?;
+        }
+        Ok(Some(scale))
+    }
+
+    /// Reads a Scala scale, mapped linearly until a keyboard mapping is read.
+    pub fn from_scl(text: &str) -> Result<Self, String> {
+        let pitches = parse_scl(text)?;
+        Ok(Self {
+            octave_degree: pitches.len() as i32,
+            pitches,
+            map: Vec::new(),
+            keys: (0, 127),
+            middle: 60,
+            reference: (69, 440.0),
+        })
+    }
+
+    /// Reads the keyboard mapping: its size, the first and last key to retune, the middle and
+    /// reference keys, the reference frequency and the octave degree, then the degree of each
+    /// key of the mapping or `x` for none.
+    pub fn parse_kbm(&mut self, text: &str) -> Result<(), String> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !l.starts_with('!') && !l.trim().is_empty())
+            .map(|(i, l)| (i + 1, l.split_whitespace().next().unwrap_or("")));
+        let mut value = |what: &'static str| {
+            let (line, token) = lines.next().ok_or_else(|| format!("missing {}", what))?;
+            Ok::<_, String>((what, line, token))
+        };
+        let key = |(what, line, token): (&str, usize, &str)| {
+            token
+                .parse::<u8>()
+                .ok()
+                .filter(|&k| k <= 127)
+                .ok_or_else(|| format!("line {}: invalid {} {}", line, what, token))
+        };
+
+        let (_, line, size) = value("map size")?;
+        let size: usize = size
+            .parse()
+            .map_err(|_| format!("line {}: invalid map size {}", line, size))?;
+        self.keys = (key(value("first key")?)?, key(value("last key")?)?);
+        self.middle = key(value("middle key")?)?.into();
+        let reference = key(value("reference key")?)?;
+        let (_, line, freq) = value("reference frequency")?;
+        let freq: f64 = freq
+            .parse()
+            .ok()
+            .filter(|&f: &f64| f > 0.0)
+            .ok_or_else(|| format!("line {}: invalid reference frequency {}", line, freq))?;
+        self.reference = (reference.into(), freq);
+        let (_, line, octave) = value("octave degree")?;
+        self.octave_degree = octave
+            .parse()
+            .ok()
+            .filter(|&d| d >= 0)
+            .ok_or_else(|| format!("line {}: invalid octave degree {}", line, octave))?;
+        self.map = (0..size)
+            .map(|_| {
+                let (_, line, degree) = value("key mapping")?;
+                match degree {
+                    "x" | "X" => Ok(None),
+                    _ => degree
+                        .parse()
+                        .map(Some)
+                        .map_err(|_| format!("line {}: invalid degree {}", line, degree)),
+                }
+            })
+            .collect::<Result<_, String>>()?;
+
+        if self.keys.0 > self.keys.1 {
+            return Err("the first key is above the last key".into());
+        }
+        if self.map.is_empty() && self.octave_degree == 0 {
+            self.octave_degree = self.pitches.len() as i32;
+        }
+        if self.degree(self.reference.0).is_none() {
+            return Err(format!("reference key {} is not mapped", self.reference.0));
+        }
+        Ok(())
+    }
+
+    /// Returns the degree of the scale a key is mapped to, if any.
+    pub fn degree(&self, key: i32) -> Option<i32> {
+        let offset = key - self.middle;
+        if self.map.is_empty() {
+            return Some(offset);
+        }
+        let size = self.map.len() as i32;
+        Some(
+            self.map[offset.rem_euclid(size) as usize]?
+                + offset.div_euclid(size) * self.octave_degree,
+        )
+    }
+
+    /// Returns the pitch of a degree relative to degree 0 (in cents).
+    pub fn cents(&self, degree: i32) -> f64 {
+        let n = self.pitches.len() as i32;
+        let period = degree.div_euclid(n) as f64 * self.pitches[n as usize - 1];
+        match degree.rem_euclid(n) {
+            0 => period,
+            step => period + self.pitches[step as usize - 1],
+        }
+    }
+
+    /// Returns the nearest key to the pitch of a key in the scale, along with the pitch bend
+    /// (in cents, within ±50) to reach it. Returns None for keys that aren't mapped.
+    pub fn retune(&self, key: u8) -> Option<(u8, f64)> {
+        if key < self.keys.0 || key > self.keys.1 {
+            return None;
+        }
+        // The reference key is mapped, as checked when reading the mapping
+        let reference = self.cents(self.degree(self.reference.0)?);
+        let cents = self.cents(self.degree(key.into())?) - reference;
+        let pitch = 69.0 + 12.0 * (self.reference.1 / 440.0).log2() + cents / 100.0;
+        let nearest = pitch.round();
+        (0.0..=127.0)
+            .contains(&nearest)
+            .then_some((nearest as u8, (pitch - nearest) * 100.0))
+    }
+}
+
+/// Reads the pitches of a Scala scale: after a description line, the number of pitches, then
+/// each pitch in cents (with a period) or as a ratio. Lines starting with `!` are comments.
+pub fn parse_scl(text: &str) -> Result<Vec<f64>, String> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.starts_with('!'))
+        .map(|(i, l)| (i + 1, l.split_whitespace().next().unwrap_or("")));
+    lines.next().ok_or("missing description")?;
+    let (line, count) = lines.next().ok_or("missing number of pitches")?;
+    let count: usize = count
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("line {}: invalid number of pitches {}", line, count))?;
+
+    let pitches: Vec<f64> = lines
+        .take(count)
+        .map(|(line, pitch)| {
+            let cents = match pitch.contains('.') {
+                true => pitch.parse().ok(),
+                false => {
+                    let (num, denom) = pitch.split_once('/').unwrap_or((pitch, "1"));
+                    match (num.parse::<u64>(), denom.parse::<u64>()) {
+                        (Ok(num), Ok(denom)) if num > 0 && denom > 0 => {
+                            Some(1200.0 * (num as f64 / denom as f64).log2())
+                        }
+                        _ => None,
+                    }
+                }
+            };
+            cents.ok_or_else(|| format!("line {}: invalid pitch {}", line, pitch))
+        })
+        .collect::<Result<_, _>>()?;
+    if pitches.len() < count {
+        return Err(format!("{} of {} pitches given", pitches.len(), count));
+    }
+    if pitches[count - 1] <= 0.0 {
+        return Err("the last pitch, the period, must be above 1/1".into());
+    }
+    Ok(pitches)
+}
+
+/// Silence rendered after the end of each track to let the last notes decay (µs).
+pub const PADDING: usize = 1_500_000;
+
+/// Returns the longest release time (in seconds) of the regions of a preset.
+pub fn preset_release(sf: &rustysynth::SoundFont, bank: u16, preset: u8) -> Option<f32> {
+    let preset = sf.get_presets().iter().find(|p| {
+        p.get_bank_number() == i32::from(bank) && p.get_patch_number() == i32::from(preset)
+    })?;
+    preset
+        .get_regions()
+        .iter()
+        .flat_map(|pr| {
+            let instrument = &sf.get_instruments()[pr.get_instrument_id()];
+            instrument
+                .get_regions()
+                .iter()
+                .map(move |ir| ir.get_release_volume_envelope() * pr.get_release_volume_envelope())
+        })
+        .reduce(f32::max)
+}
+
+/// Returns the silence to render after the end of a track (µs): the configured release tail,
+/// or else enough for the release of the patch, up to 20s.
+pub fn release_padding(sf: &rustysynth::SoundFont, instr: &InstrumentSetting) -> usize {
+    match instr.release_tail_ms {
+        Some(ms) => (ms.max(0.0) * 1_000.0) as usize,
+        None => preset_release(sf, instr.bank, instr.preset).map_or(PADDING, |secs| {
+            ((secs.min(20.0) * 1e6) as usize).max(PADDING)
+        }),
+    }
+}
+
+/// Returns the length of a track's render (µs), capped to the tighter of the global and
+/// per-instrument limits.
+pub fn render_length(
+    track: &PlayerTrack,
+    instr: &InstrumentSetting,
+    padding: usize,
+    limit: Option<usize>,
+) -> usize {
+    [limit, instr.max_len_ms.map(|ms| ms * 1_000)]
+        .into_iter()
+        .flatten()
+        .fold(track.length + padding, usize::min)
+}
+
+/// The state of rendering a track block by block, between calls of `Renderer::render_block`.
+pub struct RenderStream {
+    pub sc: usize, // samples
+    pub si: usize, // start of the next block
+    pub strummed: Option<Vec<PlayerEvent>>,
+    pub next: usize, // next event to start
+    pub offs: std::collections::BinaryHeap<std::cmp::Reverse<(usize, i32, i32)>>,
+    // Sounding notes by start, with their release, channel and velocity, to steal one beyond
+    // max_voices
+    pub voices: std::collections::VecDeque<(usize, i32, i32, i32)>,
+    pub lanes: Vec<ControlLane>,
+    pub silent: usize, // samples of silence while no notes were held
+    // Notes with different pitch bends or crossfade levels get channels of their own
+    pub channels: ChannelPool,
+    pub mpe: Option<MpeState>,
+}
+
+/// The expression of the notes of an MPE instrument: the pitch bend and pressure last set on
+/// each channel of the MIDI file, along with the synthesizer channel of its latest note and
+/// that note's retuning (in steps of pitch bend).
+pub struct MpeState {
+    pub next: usize, // next expression to apply
+    pub sources: [MpeSource; 16],
+}
+
+pub type MpeSource = (i32, u8, Option<(i32, i32)>);
+
+/// A synthesizer channel of a track, as tracked to share it among notes of the same pitch bend
+/// and crossfade level.
+/// The expression (14-bit) of a channel at CC11 = 127, which rustysynth also resets it to.
+pub const FULL_EXPRESSION: i32 = 127 << 7;
+
+pub struct PooledChannel {
+    pub channel: i32,
+    pub bend: i32,
+    pub level: i32,      // expression (14-bit) the controller automation is scaled to
+    pub held: usize,     // notes
+    pub released: usize, // time of the last release (µs)
+}
+
+pub struct ChannelPool(pub Vec<PooledChannel>);
+
+impl ChannelPool {
+    pub fn new(channels: Vec<i32>) -> Self {
+        Self(
+            channels
+                .into_iter()
+                .map(|channel| PooledChannel {
+                    channel,
+                    bend: 8192,
+                    level: FULL_EXPRESSION,
+                    held: 0,
+                    released: 0,
+                })
+                .collect(),
+        )
+    }
+
+    /// Picks the channel for a note with a pitch bend and level: if shared, one set so
+    /// already, else the free channel released the longest ago, else the one holding the
+    /// fewest notes. Returns the channel and whether it has to be bent and leveled.
+    pub fn hold(&mut self, bend: i32, level: i32, shared: bool) -> (i32, bool, bool) {
+        let c = match self
+            .0
+            .iter()
+            .position(|c| shared && c.bend == bend && c.level == level)
+        {
+            Some(i) => &mut self.0[i],
+            None => self
+                .0
+                .iter_mut()
+                .min_by_key(|c| (c.held, c.released))
+                .unwrap(),
+        };
+        let changed = (c.bend != bend, c.level != level);
+        (c.bend, c.level) = (bend, level);
+        c.held += 1;
+        (c.channel, changed.0, changed.1)
+    }
+
+    /// Records a change of the pitch bend of a channel, returning whether it changed.
+    pub fn bend(&mut self, channel: i32, bend: i32) -> bool {
+        match self.0.iter_mut().find(|c| c.channel == channel) {
+            Some(c) if c.bend != bend => {
+                c.bend = bend;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the release of a note held on a channel at the given time (µs).
+    pub fn release(&mut self, channel: i32, time: usize) {
+        if let Some(c) = self.0.iter_mut().find(|c| c.channel == channel) {
+            c.held = c.held.saturating_sub(1);
+            c.released = time;
+        }
+    }
+}
+
+/// Inverts the polarity and scales the stereo width of a track, as configured. Both apply to
+/// each sample on its own, so that this works on blocks of a track just as well.
+pub fn shape_image(left: &mut [f32], right: &mut [f32], instr: &InstrumentSetting) {
+    if instr.invert {
+        left.iter_mut()
+            .chain(right.iter_mut())
+            .for_each(|s| *s = -*s);
+    }
+
+    if let Some(width) = instr.width {
+        // Scale the side (difference) signal relative to the mid (sum) signal
+        let width = width.max(0.0);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let mid = (*l + *r) / 2.0;
+            let side = (*l - *r) / 2.0 * width;
+            *l = mid + side;
+            *r = mid - side;
+        }
+    }
+}
+
+pub struct Renderer {
+    pub synth: rustysynth::Synthesizer,
+    pub track: PlayerTrack,
+    pub tuning: f32, // semitones
+    pub scale: Option<std::sync::Arc<Scale>>,
+    // Notes stolen beyond max_voices, and notes over polyphony: notes started while as many
+    // notes as the synthesizer has voices were held. Voices still sounding in their release
+    // are not counted, as rustysynth doesn't report them, so this is a lower bound of the
+    // notes that made it steal a voice.
+    pub stolen: (usize, usize),
+}
+
+impl Renderer {
+    pub fn render(
+        &mut self,
+        instr: &InstrumentSetting,
+        padding: usize,
+        limit: Option<usize>,
+        progress: &dyn Progress,
+    ) -> (Vec<f32>, Vec<f32>) {
+        let sr: usize = self.synth.get_sample_rate() as usize;
+        let bs: usize = self.synth.get_block_size();
+
+        let trim = self.trim(instr);
+        let mut stream = self.start(instr, padding, limit);
+        let sc = stream.sc;
+
+        let mut left: Vec<f32> = vec![0_f32; sc];
+        let mut right: Vec<f32> = vec![0_f32; sc];
+
+        progress.update(Stage::Rendering, 0, sc as u64);
+
+        for si in (0..sc).step_by(bs) {
+            let end = (si + bs).min(sc);
+            self.render_block(&mut stream, instr, &mut left[si..end], &mut right[si..end]);
+            progress.update(Stage::Rendering, end as u64, sc as u64);
+        }
+
+        if let Some(trim) = trim {
+            left.iter_mut()
+                .chain(right.iter_mut())
+                .for_each(|s| *s *= trim);
+        }
+
+        if instr.attack.is_some()
+            || instr.decay.is_some()
+            || instr.sustain.is_some()
+            || instr.release.is_some()
+        {
+            // The envelope follows the notes as played, strummed or not
+            let events = stream.strummed.as_deref().unwrap_or(&self.track.events);
+            self.envelope(events, &mut left, &mut right, instr);
+        }
+
+        if let Some(mut delay_l) = self.delay(instr) {
+            let mut delay_r = delay_l.clone();
+            delay_l.process(&mut left);
+            delay_r.process(&mut right);
+        }
+
+        if let Some(threshold) = instr.gate_db {
+            noise_gate(&mut left, &mut right, threshold, sr);
+        }
+
+        shape_image(&mut left, &mut right, instr);
+
+        (left, right)
+    }
+
+    /// Returns the gain that brings the instrument to its configured `level`, if any. Call this
+    /// before setting up the instrument, as measuring resets the synthesizer.
+    pub fn trim(&mut self, instr: &InstrumentSetting) -> Option<f32> {
+        let level = instr.level?;
+        let measured = self.reference_level(instr)?;
+        Some(10f32.powf((level - measured) / 20.0))
+    }
+
+    /// Returns the RMS level (in dBFS) of the first 500ms of a reference note played with the
+    /// instrument's preset: middle C at velocity 100, or the track's first note if middle C is
+    /// silent. Resets the synthesizer afterwards. Returns None if both are silent.
+    pub fn reference_level(&mut self, instr: &InstrumentSetting) -> Option<f32> {
+        let sr: usize = self.synth.get_sample_rate() as usize;
+        let notes = std::iter::once(60).chain(self.track.events.first().map(|e| e.note.into()));
+
+        let mut level = None;
+        for note in notes {
+            self.synth
+                .process_midi_message(0, 0xb0, 0x00, instr.bank.into());
+            self.synth
+                .process_midi_message(0, 0xc0, instr.preset.into(), 0);
+            self.synth.note_on(0, note, 100);
+
+            let mut left = vec![0_f32; sr / 2];
+            let mut right = vec![0_f32; sr / 2];
+            self.synth.render(&mut left, &mut right);
+            self.synth.reset();
+
+            let energy = left
+                .iter()
+                .chain(right.iter())
+                .map(|s| f64::from(*s) * f64::from(*s))
+                .sum::<f64>();
+            if energy > 0.0 {
+                level = Some((10.0 * (energy / (2 * left.len()) as f64).log10()) as f32);
+                break;
+            }
+        }
+        level
+    }
+
+    /// Sets up the synthesizer for an instrument and returns the state of rendering the track
+    /// block by block with `render_block`.
+    pub fn start(
+        &mut self,
+        instr: &InstrumentSetting,
+        padding: usize,
+        limit: Option<usize>,
+    ) -> RenderStream {
+        let sr: usize = self.synth.get_sample_rate() as usize;
+        let bs: usize = self.synth.get_block_size();
+
+        let length = render_length(&self.track, instr, padding, limit);
+
+        // A scale, MPE or crossfade spreads the notes over all channels but the percussion one
+        let channels: Vec<i32> =
+            match self.scale.is_some() || instr.mpe.is_some() || instr.xfade.is_some() {
+                true => (0..16).filter(|&ch| ch != 9).collect(),
+                false => vec![0],
+            };
+
+        for &ch in channels.iter() {
+            self.setup_channel(ch, instr);
+        }
+
+        RenderStream {
+            sc: length * sr / 1_000_000,
+            si: 0,
+            strummed: instr.strum_ms.map(|ms| {
+                strum(
+                    &self.track.events,
+                    (ms.max(0.0) * 1_000.0) as usize,
+                    instr.strum_down,
+                )
+            }),
+            next: 0,
+            offs: std::collections::BinaryHeap::new(),
+            voices: std::collections::VecDeque::new(),
+            // Controller automation from the MIDI file is only applied on request. Volume (CC7)
+            // and expression (CC11) get lanes of their own, which the synthesizer multiplies
+            // per voice.
+            lanes: match instr.cc_interp {
+                Some(interp) => [7, 10, 11]
+                    .into_iter()
+                    .map(|cc| ControlLane::new(&self.track.controls, cc, interp, sr, bs))
+                    .filter(|lane| !lane.points.is_empty())
+                    .collect(),
+                None => Vec::new(),
+            },
+            silent: 0,
+            channels: ChannelPool::new(channels),
+            mpe: instr.mpe.map(|_| MpeState {
+                next: 0,
+                sources: [(8192, 0, None); 16],
+            }),
+        }
+    }
+
+    /// Sets the preset, reverb send and tuning of a synthesizer channel for an instrument.
+    pub fn setup_channel(&mut self, ch: i32, instr: &InstrumentSetting) {
+        // Setup instruments using MIDI control messages, as Synthesizer has no API for this. Its
+        // bank select takes the full bank number, so 14-bit banks need no separate LSB.
+        self.synth
+            .process_midi_message(ch, 0xb0, 0x00, instr.bank.into());
+        self.synth
+            .process_midi_message(ch, 0xc0, instr.preset.into(), 0);
+        if let Some(send) = instr.reverb_send {
+            let value = (send.clamp(0.0, 1.0) * 127.0).round() as i32;
+            self.synth.process_midi_message(ch, 0xb0, 0x5b, value);
+        }
+        if self.tuning != 0.0 {
+            // Coarse (RPN 2) and fine (RPN 1) tuning, then deselect the RPN
+            let coarse = self.tuning.round();
+            let fine = (8192.0 + (self.tuning - coarse) * 8192.0).round() as i32;
+            for (cc, value) in [
+                (0x65, 0),
+                (0x64, 2),
+                (0x06, coarse as i32 + 64),
+                (0x64, 1),
+                (0x06, fine >> 7),
+                (0x26, fine & 0x7f),
+                (0x65, 0x7f),
+                (0x64, 0x7f),
+            ] {
+                self.synth.process_midi_message(ch, 0xb0, cc, value);
+            }
+        }
+        if let Some(range) = instr.mpe {
+            // Pitch bend range (RPN 0), then deselect the RPN
+            for (cc, value) in [
+                (0x65, 0),
+                (0x64, 0),
+                (0x06, range.into()),
+                (0x26, 0),
+                (0x65, 0x7f),
+                (0x64, 0x7f),
+            ] {
+                self.synth.process_midi_message(ch, 0xb0, cc, value);
+            }
+        }
+    }
+
+    /// Renders the next block of the track, of the synthesizer's block size or less at the end.
+    pub fn render_block(
+        &mut self,
+        stream: &mut RenderStream,
+        instr: &InstrumentSetting,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let sr: usize = self.synth.get_sample_rate() as usize;
+        let bs: usize = self.synth.get_block_size();
+        let t = (stream.si * 1_000_000) / sr;
+        stream.si += left.len();
+
+        let transpose = instr.transpose.unwrap_or(0);
+        // Very short notes are lengthened, as they can click with some patches
+        let min_duration = instr
+            .min_note_ms
+            .map_or(0, |ms| (ms.max(0.0) * 1_000.0) as usize);
+
+        for lane in stream.lanes.iter_mut() {
+            if let Some(value) = lane.advance(t) {
+                let cc = i32::from(lane.controller);
+                for c in stream.channels.0.iter() {
+                    // Send the value with 14-bit resolution using the controller's LSB, with
+                    // expression scaled to the crossfade level of the channel
+                    let full = if cc == 11 { c.level } else { 16383 };
+                    let value = (value / 127.0 * full as f32).round() as i32;
+                    let ch = c.channel;
+                    self.synth.process_midi_message(ch, 0xb0, cc, value >> 7);
+                    self.synth
+                        .process_midi_message(ch, 0xb0, cc + 32, value & 0x7f);
+                }
+            }
+        }
+
+        // The pitch bend and pressure of each channel of the file follow its latest note onto
+        // that note's channel. rustysynth has no aftertouch, so pressure drives the modulation
+        // (CC1), which most soundfonts turn into vibrato.
+        if let Some(mpe) = stream.mpe.as_mut() {
+            while let Some(x) = self.track.expressions.get(mpe.next).filter(|x| x.time <= t) {
+                mpe.next += 1;
+                let source = &mut mpe.sources[usize::from(x.channel)];
+                match x.kind {
+                    Expression::Bend(bend) => source.0 = bend,
+                    Expression::Pressure(pressure) => source.1 = pressure,
+                }
+                let Some((ch, retune)) = source.2 else {
+                    continue;
+                };
+                match x.kind {
+                    Expression::Bend(bend) => {
+                        let bend = (bend + retune).clamp(0, 16383);
+                        if stream.channels.bend(ch, bend) {
+                            self.synth
+                                .process_midi_message(ch, 0xe0, bend & 0x7f, bend >> 7);
+                        }
+                    }
+                    Expression::Pressure(pressure) => {
+                        self.synth
+                            .process_midi_message(ch, 0xb0, 0x01, pressure.into());
+                    }
+                }
+            }
+        }
+
+        // Release notes before starting new ones, so that retriggered notes keep sounding
+        while let Some(&std::cmp::Reverse((time, note, ch))) = stream.offs.peek()
+            && time <= t
+        {
+            stream.offs.pop();
+            if instr.max_voices.is_some() {
+                // Stolen notes have already been released
+                let Some(i) = stream
+                    .voices
+                    .iter()
+                    .position(|&(t, n, c, _)| (t, n, c) == (time, note, ch))
+                else {
+                    continue;
+                };
+                stream.voices.remove(i);
+            }
+            self.synth.note_off(ch, note);
+            stream.channels.release(ch, time);
+        }
+
+        let events = stream.strummed.as_deref().unwrap_or(&self.track.events);
+        while let Some(e) = events.get(stream.next).filter(|e| e.time <= t) {
+            stream.next += 1;
+            // Notes transposed out of the MIDI range are dropped
+            let Some(note) = e.note.checked_add_signed(transpose).filter(|&n| n <= 127) else {
+                continue;
+            };
+            // A scale plays the nearest key, bent to its pitch on a channel of its own
+            let (note, retune) = match &self.scale {
+                Some(scale) => match scale.retune(note) {
+                    Some((note, cents)) => {
+                        let range = f64::from(instr.mpe.unwrap_or(2)) * 100.0;
+                        (note, (cents / range * 8192.0).round() as i32)
+                    }
+                    None => continue,
+                },
+                None => (note, 0),
+            };
+            let source = usize::from(e.channel);
+            let bend = match &stream.mpe {
+                Some(mpe) => (mpe.sources[source].0 + retune).clamp(0, 16383),
+                None => 8192 + retune,
+            };
+            // Layers crossfading by velocity sound with equal-power gains in the crossfade, by
+            // the expression of the note's channel, which the synthesizer squares
+            let level = match instr.xfade {
+                Some((lo, hi)) => {
+                    let x = (f32::from(e.velocity) - f32::from(lo)) / f32::from(hi - lo);
+                    let angle = x.clamp(0.0, 1.0) * std::f32::consts::FRAC_PI_2;
+                    let gain = if instr.xfade_up {
+                        angle.sin()
+                    } else {
+                        angle.cos()
+                    };
+                    if gain < 1e-3 {
+                        continue;
+                    }
+                    (gain.sqrt() * FULL_EXPRESSION as f32).round() as i32
+                }
+                None => FULL_EXPRESSION,
+            };
+            let velocity = match (instr.fixed_velocity, instr.vel_trim) {
+                // A fixed velocity ignores the dynamics of the file altogether
+                (Some(velocity), _) => velocity.into(),
+                (None, Some(trim)) => {
+                    (f32::from(e.velocity) * trim).round().clamp(1.0, 127.0) as i32
+                }
+                (None, None) => e.velocity.into(),
+            };
+            if let Some(max_voices) = instr.max_voices {
+                while stream.voices.len() >= max_voices {
+                    let i = match instr.voice_steal {
+                        VoiceSteal::Oldest => 0,
+                        VoiceSteal::Quietest => (0..stream.voices.len())
+                            .min_by_key(|&i| stream.voices[i].3)
+                            .unwrap(),
+                    };
+                    let (_, stolen, ch, _) = stream.voices.remove(i).unwrap();
+                    self.synth.note_off(ch, stolen);
+                    stream.channels.release(ch, e.time);
+                    self.stolen.0 += 1;
+                }
+            }
+            // Every held note takes at least one voice, which rustysynth steals when none is free
+            let held: usize = stream.channels.0.iter().map(|c| c.held).sum();
+            if held >= self.synth.get_maximum_polyphony() {
+                self.stolen.1 += 1;
+            }
+            // MPE notes get a channel of their own, to be bent and pressed on their own
+            let (ch, bent, leveled) = stream.channels.hold(bend, level, stream.mpe.is_none());
+            if bent {
+                self.synth
+                    .process_midi_message(ch, 0xe0, bend & 0x7f, bend >> 7);
+            }
+            if leveled {
+                let lane = stream.lanes.iter().find(|lane| lane.controller == 11);
+                let value = lane.and_then(|lane| lane.value).unwrap_or(127.0);
+                let value = (value / 127.0 * level as f32).round() as i32;
+                self.synth.process_midi_message(ch, 0xb0, 0x0b, value >> 7);
+                self.synth
+                    .process_midi_message(ch, 0xb0, 0x2b, value & 0x7f);
+            }
+            if let Some(mpe) = stream.mpe.as_mut() {
+                for other in mpe.sources.iter_mut() {
+                    other.2 = other.2.filter(|&(other, _)| other != ch);
+                }
+                mpe.sources[source].2 = Some((ch, retune));
+                let pressure = mpe.sources[source].1;
+                self.synth
+                    .process_midi_message(ch, 0xb0, 0x01, pressure.into());
+            }
+            self.synth.note_on(ch, note.into(), velocity);
+            let duration = e.duration.max(min_duration);
+            let off = (e.time + duration, i32::from(note), ch);
+            stream.offs.push(std::cmp::Reverse(off));
+            if instr.max_voices.is_some() {
+                stream.voices.push_back((off.0, off.1, off.2, velocity));
+            }
+        }
+
+        // rustysynth can't tell whether voices are still sounding, so once the output has been
+        // inaudible for a while with no notes held, including any reverb tail, the synthesizer
+        // is skipped until the next note starts, as during rests and after the last note
+        const SILENCE: f32 = 1e-6; // -120 dBFS
+        if stream.silent >= sr / 5 && stream.offs.is_empty() {
+            left.fill(0.0);
+            right.fill(0.0);
+            stream.silent += left.len();
+            return;
+        }
+
+        if left.len() == bs {
+            self.synth.render(left, right);
+        } else {
+            // Render a final partial block into a scratch block and keep the valid part
+            let mut block_left = vec![0_f32; bs];
+            let mut block_right = vec![0_f32; bs];
+            self.synth.render(&mut block_left, &mut block_right);
+            left.copy_from_slice(&block_left[..left.len()]);
+            right.copy_from_slice(&block_right[..right.len()]);
+        }
+
+        if stream.offs.is_empty() && left.iter().chain(right.iter()).all(|s| s.abs() < SILENCE) {
+            stream.silent += left.len();
+        } else {
+            stream.silent = 0;
+        }
+    }
+
+    /// Shapes the track with an attack/decay/sustain/release envelope per note. rustysynth can't
+    /// override the soundfont's envelope generators, so this is applied to the rendered output
+    /// instead: every sample is scaled by the loudest envelope among the notes sounding at that
+    /// time, on top of the patch's own envelope. Without a release, a note's level is held until
+    /// the next note starts so that the patch's natural tail is kept.
+    pub fn envelope(
+        &self,
+        events: &[PlayerEvent],
+        left: &mut [f32],
+        right: &mut [f32],
+        instr: &InstrumentSetting,
+    ) {
+        let sr = self.synth.get_sample_rate() as f32;
+        let samples = |ms: Option<f32>| ms.map(|ms| (ms.max(0.0) * sr / 1_000.0) as usize);
+        let attack = samples(instr.attack).unwrap_or(0);
+        let decay = samples(instr.decay).unwrap_or(0);
+        let sustain = instr.sustain.unwrap_or(1.0).clamp(0.0, 1.0);
+        let release = samples(instr.release);
+
+        let level = |i: usize| {
+            if i < attack {
+                i as f32 / attack as f32
+            } else if i < attack + decay {
+                1.0 - (1.0 - sustain) * (i - attack) as f32 / decay as f32
+            } else {
+                sustain
+            }
+        };
+
+        let to_sample =
+            |us: usize| ((us as f64 * sr as f64 / 1_000_000.0) as usize).min(left.len());
+        let mut gain = vec![0_f32; left.len()];
+        for (n, e) in events.iter().enumerate() {
+            let start = to_sample(e.time);
+            let end = to_sample(e.time + e.duration).max(start);
+            for (i, g) in gain[start..end].iter_mut().enumerate() {
+                *g = g.max(level(i));
+            }
+
+            let held = level(end - start);
+            match release {
+                Some(release) => {
+                    let stop = (end + release).min(gain.len());
+                    for (i, g) in gain[end..stop].iter_mut().enumerate() {
+                        *g = g.max(held * (1.0 - i as f32 / release as f32));
+                    }
+                }
+                None => {
+                    let next = events.get(n + 1).map_or(gain.len(), |e| to_sample(e.time));
+                    for g in gain[end..next.max(end)].iter_mut() {
+                        *g = g.max(held);
+                    }
+                }
+            }
+        }
+
+        for ((l, r), g) in left.iter_mut().zip(right.iter_mut()).zip(gain) {
+            *l *= g;
+            *r *= g;
+        }
+    }
+
+    /// Returns a feedback delay whose length follows the tempo at the track's notes.
+    pub fn delay(&self, instr: &InstrumentSetting) -> Option<TempoDelay> {
+        let sr = self.synth.get_sample_rate() as usize;
+        let beats = f64::from(instr.delay_beats?);
+        let tempos: Vec<_> = self
+            .track
+            .events
+            .iter()
+            .map(|e| (e.time * sr / 1_000_000, e.tempo))
+            .collect();
+        let tempo = tempos.first().map_or(500_000, |t| t.1);
+        let slowest = tempos.iter().map(|t| t.1).max().unwrap_or(tempo);
+
+        let mut delay = TempoDelay {
+            tempos,
+            next: 0,
+            tempo,
+            beats,
+            sr,
+            feedback: instr.delay_feedback.unwrap_or(0.35).clamp(0.0, 0.99),
+            mix: instr.delay_mix.unwrap_or(0.5).clamp(0.0, 1.0),
+            dry: Vec::new(),
+            wet: Vec::new(),
+            pos: 0,
+        };
+        // The lines must hold the longest delay, at the slowest tempo
+        delay.dry = vec![0.0; delay.length(slowest) + 1];
+        delay.wet = delay.dry.clone();
+        Some(delay)
+    }
+}
+
+/// A feedback delay applied to a channel block by block, whose length in samples follows the
+/// tempo at the track's notes.
+#[derive(Clone)]
+pub struct TempoDelay {
+    pub tempos: Vec<(usize, usize)>, // sample, µs per beat
+    pub next: usize,                 // index of the next tempo in `tempos`
+    pub tempo: usize,
+    pub beats: f64,
+    pub sr: usize,
+    pub feedback: f32,
+    pub mix: f32,
+    pub dry: Vec<f32>, // the last samples in, in a ring buffer indexed by position
+    pub wet: Vec<f32>, // the delayed signal, likewise
+    pub pos: usize,    // samples processed
+}
+
+impl TempoDelay {
+    /// Returns the length of the delay (in samples) at a tempo.
+    pub fn length(&self, tempo: usize) -> usize {
+        (self.beats * tempo as f64 * self.sr as f64 / 1_000_000.0) as usize
+    }
+
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let ring = self.dry.len();
+        for (i, s) in (self.pos..).zip(buf.iter_mut()) {
+            while let Some(&(_, t)) = self.tempos.get(self.next).filter(|t| t.0 <= i) {
+                self.tempo = t;
+                self.next += 1;
+            }
+            let d = self.length(self.tempo);
+            let wet = match d > 0 && i >= d {
+                true => self.dry[(i - d) % ring] + self.feedback * self.wet[(i - d) % ring],
+                false => 0.0,
+            };
+            self.dry[i % ring] = *s;
+            self.wet[i % ring] = wet;
+            *s += self.mix * wet;
+        }
+        self.pos += buf.len();
+    }
+}
+
+/// The automation of a single controller, evaluated once per rendered block.
+pub struct ControlLane {
+    pub controller: u8,
+    pub points: Vec<(usize, f32)>, // time (µs), value
+    pub next: usize,
+    pub interp: CcInterp,
+    pub alpha: f32, // per-block smoothing factor
+    pub value: Option<f32>,
+}
+
+impl ControlLane {
+    pub fn new(
+        controls: &[PlayerControl],
+        controller: u8,
+        interp: CcInterp,
+        sr: usize,
+        bs: usize,
+    ) -> Self {
+        Self {
+            controller,
+            points: controls
+                .iter()
+                .filter(|c| c.controller == controller)
+                .map(|c| (c.time, f32::from(c.value)))
+                .collect(),
+            next: 0,
+            interp,
+            alpha: 1.0 - (-(bs as f32) / (sr as f32 * 0.020)).exp(),
+            value: None,
+        }
+    }
+
+    /// Returns the controller value at time `t` (µs), if it changed since the previous block.
+    pub fn advance(&mut self, t: usize) -> Option<f32> {
+        while self.points.get(self.next).is_some_and(|p| p.0 <= t) {
+            self.next += 1;
+        }
+        // Before the first change, the synthesizer's default applies
+        let &(time, target) = self.points.get(self.next.checked_sub(1)?)?;
+
+        let value = match (self.interp, self.points.get(self.next), self.value) {
+            (CcInterp::Linear, Some(&(n_time, n_value)), _) => {
+                target + (n_value - target) * (t - time) as f32 / (n_time - time) as f32
+            }
+            (CcInterp::Smooth, _, Some(value)) => value + (target - value) * self.alpha,
+            _ => target,
+        };
+
+        if self.value.is_some_and(|v| (v - value).abs() < 1e-3) {
+            return None;
+        }
+        self.value = Some(value);
+        Some(value)
+    }
+}
+
+/// Spreads the notes of chords (notes starting within 20ms of the first) over time, each note
+/// starting `step` µs after the previous one, in ascending or descending pitch order. The notes
+/// keep their original end times.
+pub fn strum(events: &[PlayerEvent], step: usize, descending: bool) -> Vec<PlayerEvent> {
+    const CHORD_WINDOW: usize = 20_000; // µs
+
+    let mut out = Vec::with_capacity(events.len());
+    let mut rest = events;
+    while let Some(first) = rest.first() {
+        // Measured from the onset, so that fast runs don't chain into a single chord
+        let onset = first.time;
+        let len = rest
+            .iter()
+            .take_while(|e| e.time <= onset + CHORD_WINDOW)
+            .count();
+        let (chord, next) = rest.split_at(len);
+        rest = next;
+        let mut chord = chord.to_vec();
+        chord.sort_by_key(|e| e.note);
+        if descending {
+            chord.reverse();
+        }
+
+        for (i, mut e) in chord.into_iter().enumerate() {
+            let end = e.time + e.duration;
+            e.time = e.time.max(onset + i * step);
+            e.duration = end.saturating_sub(e.time);
+            out.push(e);
+        }
+    }
+
+    out.sort_by_key(|e| e.time);
+    out
+}
+
+/// Synthesizes a short click at the pitch and velocity of every note, in place of rendering the
+/// track with the synthesizer.
+pub fn render_clicks(track: &PlayerTrack, sr: usize, length: usize) -> (Vec<f32>, Vec<f32>) {
+    let sc = length * sr / 1_000_000;
+    let mut out = vec![0_f32; sc];
+
+    let click_len = sr * 10 / 1_000; // 10ms
+    for e in track.events.iter() {
+        let freq = 440.0 * 2f32.powf((f32::from(e.note) - 69.0) / 12.0);
+        let amp = 0.3 * f32::from(e.velocity) / 127.0;
+        let start = e.time * sr / 1_000_000;
+        for (i, s) in out.iter_mut().skip(start).take(click_len).enumerate() {
+            let t = i as f32 / sr as f32;
+            let env = 1.0 - (i as f32 / click_len as f32);
+            *s += amp * env * (2.0 * std::f32::consts::PI * freq * t).sin();
+        }
+    }
+
+    (out.clone(), out)
+}
+
+/// Synthesizes a click on every beat, with a higher-pitched accent on downbeats.
+pub fn render_metronome(timemap: &TimeMap, sr: usize, length: usize) -> (Vec<f32>, Vec<f32>) {
+    let sc = length * sr / 1_000_000;
+    let mut out = vec![0_f32; sc];
+
+    let click_len = sr * 30 / 1_000; // 30ms
+    for (time, downbeat) in timemap.beats() {
+        let freq = if downbeat { 1_500.0 } else { 1_000.0 };
+        let amp = if downbeat { 0.5 } else { 0.3 };
+        let start = time * sr / 1_000_000;
+        for (i, s) in out.iter_mut().skip(start).take(click_len).enumerate() {
+            let t = i as f32 / sr as f32;
+            let env = 1.0 - (i as f32 / click_len as f32);
+            *s += amp * env * env * (2.0 * std::f32::consts::PI * freq * t).sin();
+        }
+    }
+
+    (out.clone(), out)
+}
+
+/// Silences stereo-linked windows whose RMS level falls below the threshold (in dBFS). The gate
+/// looks ahead by one window and its gain is smoothed with a short attack and a longer release,
+/// so that note onsets pass untouched and the gate doesn't chatter.
+pub fn noise_gate(left: &mut [f32], right: &mut [f32], threshold_db: f32, sr: usize) {
+    let gate = NoiseGate::new(threshold_db, sr);
+    let open: Vec<bool> = left
+        .chunks(gate.window)
+        .zip(right.chunks(gate.window))
+        .map(|(l, r)| gate.open(l, r))
+        .collect();
+
+    let mut gain = 0_f32;
+    for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+        let w = i / gate.window;
+        gain = gate.step(gain, open[w] || open.get(w + 1).copied().unwrap_or(false));
+        *l *= gain;
+        *r *= gain;
+    }
+}
+
+/// The parameters of `noise_gate`.
+pub struct NoiseGate {
+    pub threshold: f32, // RMS
+    pub window: usize,  // samples
+    pub attack: f32,    // gain per sample
+    pub release: f32,
+}
+
+impl NoiseGate {
+    pub fn new(threshold_db: f32, sr: usize) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_db / 20.0),
+            window: (sr / 100).max(1),          // 10ms
+            attack: 1.0 / (sr as f32 * 0.001),  // 1ms
+            release: 1.0 / (sr as f32 * 0.050), // 50ms
+        }
+    }
+
+    pub fn open(&self, left: &[f32], right: &[f32]) -> bool {
+        let sum: f32 = left.iter().chain(right.iter()).map(|s| s * s).sum();
+        (sum / (left.len() + right.len()) as f32).sqrt() >= self.threshold
+    }
+
+    pub fn step(&self, gain: f32, open: bool) -> f32 {
+        match open {
+            true => (gain + self.attack).min(1.0),
+            false => (gain - self.release).max(0.0),
+        }
+    }
+}
+
+/// A noise gate applied block by block. As it looks ahead by a window, it holds back the
+/// samples of the last two windows until the next is known or the track ends.
+pub struct StreamGate {
+    pub gate: NoiseGate,
+    pub gain: f32,
+    pub held: (Vec<f32>, Vec<f32>), // starting at a window
+}
+
+impl StreamGate {
+    /// Takes the next samples of the track, and replaces them by those gated so far.
+    pub fn process(&mut self, left: &mut Vec<f32>, right: &mut Vec<f32>, last: bool) {
+        let window = self.gate.window;
+        self.held.0.append(left);
+        self.held.1.append(right);
+
+        let (hl, hr) = &mut self.held;
+        let ready = match last {
+            true => hl.len(),
+            false => (hl.len() / window).saturating_sub(1) * window,
+        };
+        for w in (0..ready).step_by(window) {
+            let end = (w + window).min(hl.len());
+            let next = (end < hl.len()).then(|| {
+                let next_end = (end + window).min(hl.len());
+                self.gate.open(&hl[end..next_end], &hr[end..next_end])
+            });
+            let open = self.gate.open(&hl[w..end], &hr[w..end]) || next.unwrap_or(false);
+            for (l, r) in hl[w..end].iter_mut().zip(hr[w..end].iter_mut()) {
+                self.gain = self.gate.step(self.gain, open);
+                *l *= self.gain;
+                *r *= self.gain;
+            }
+        }
+        left.extend(hl.drain(..ready));
+        right.extend(hr.drain(..ready));
+    }
+}
+
+#[derive(Clone)]
+pub struct MixerGainFactors {
+    pub l_to_l: f32,
+    pub l_to_r: f32,
+    pub r_to_l: f32,
+    pub r_to_r: f32,
+}
+
+impl MixerGainFactors {
+    pub fn new(gain_db: f32, pan: f32) -> Self {
+        // map pan from [-1 .. 1] to [0 .. π/2] (radians)
+        let pan_rad = ((pan.clamp(-1.0, 1.0) + 1.0) / 2.0) * (std::f32::consts::PI / 2.0);
+        // convert gain from dB to factor
+        let gain = 10f32.powf(gain_db / 20.0);
+
+        Self {
+            // destination: left (cos curve)
+            l_to_l: gain * pan_rad.cos(),
+            r_to_l: gain * (pan_rad + (std::f32::consts::PI / 4.0)).cos().max(0.0),
+
+            // destination: right (sin curve)
+            r_to_r: gain * pan_rad.sin(),
+            l_to_r: gain * (pan_rad - (std::f32::consts::PI / 4.0)).sin().max(0.0),
+        }
+    }
+
+    /// Returns the gain, pan or balance of an instrument setting for a part.
+    pub fn from_setting(is: &InstrumentSetting, part: &PlayerTrack) -> Self {
+        let gain = is.gain.unwrap_or(0f32);
+        match is.balance {
+            Some(balance) => Self::balance(gain, balance),
+            None => Self::new(gain, Self::pan(is, part).unwrap_or(0f32)),
+        }
+    }
+
+    /// Returns the pan of a part: the configured one or else the file's initial pan, unless
+    /// automation applies it.
+    pub fn pan(is: &InstrumentSetting, part: &PlayerTrack) -> Option<f32> {
+        match is.cc_interp {
+            None => is.pan.or_else(|| part.initial_pan()),
+            Some(_) => is.pan,
+        }
+    }
+
+    /// Describes the pan or balance of an instrument setting for a part.
+    pub fn placement(is: &InstrumentSetting, part: &PlayerTrack) -> String {
+        match (is.balance, Self::pan(is, part)) {
+            (Some(balance), _) => format!("balance {:+.2}", balance),
+            (None, Some(pan)) => format!("pan {:+.2}", pan),
+            (None, None) => "center".into(),
+        }
+    }
+
+    /// Returns the gain factors with an additional gain (in dB) applied.
+    pub fn with_gain(&self, gain_db: f32) -> Self {
+        let gain = 10f32.powf(gain_db / 20.0);
+        Self {
+            l_to_l: gain * self.l_to_l,
+            l_to_r: gain * self.l_to_r,
+            r_to_l: gain * self.r_to_l,
+            r_to_r: gain * self.r_to_r,
+        }
+    }
+
+    /// Attenuates one side of a stereo track without mixing the channels, so that its stereo
+    /// image is kept, unlike panning it.
+    pub fn balance(gain_db: f32, balance: f32) -> Self {
+        let gain = 10f32.powf(gain_db / 20.0);
+        let balance = balance.clamp(-1.0, 1.0);
+
+        Self {
+            l_to_l: gain * (1.0 - balance).min(1.0),
+            r_to_l: 0.0,
+            r_to_r: gain * (1.0 + balance).min(1.0),
+            l_to_r: 0.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MixerTrack {
+    pub name: String,
+    pub track: Option<String>, // name of the MIDI track
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+    pub gainfactors: MixerGainFactors,
+    pub offset: isize, // samples
+    pub output: usize, // channel pair
+    pub bus: Option<String>,
+}
+
+impl MixerTrack {
+    /// Scales the track to the given peak level (in dBFS). Silent tracks are left untouched.
+    pub fn normalize(&mut self, target_db: f32) {
+        let peak = self
+            .left
+            .iter()
+            .chain(self.right.iter())
+            .fold(0_f32, |peak, s| peak.max(s.abs()));
+        if peak > 0.0 {
+            let scale = 10f32.powf(target_db / 20.0) / peak;
+            self.left
+                .iter_mut()
+                .chain(self.right.iter_mut())
+                .for_each(|s| *s *= scale);
+        }
+    }
+
+    /// Returns the mono loss (in dB) of the track as panned into the mix, or None if silent.
+    pub fn mono_loss(&self) -> Option<f32> {
+        let gf = &self.gainfactors;
+        mono_loss(self.left.iter().zip(self.right.iter()).map(|(&il, &ir)| {
+            (
+                gf.l_to_l * il + gf.r_to_l * ir,
+                gf.l_to_r * il + gf.r_to_r * ir,
+            )
+        }))
+    }
+
+    /// Adds another track into this one, applying its gain, pan and offset. Grows the buffers
+    /// as needed, so this should be a sum with unity gain and no offset.
+    pub fn accumulate(&mut self, other: &MixerTrack) {
+        let gf = &other.gainfactors;
+        let skip = usize::try_from(-other.offset).unwrap_or(0);
+        let start = usize::try_from(other.offset).unwrap_or(0);
+        let length = (other.left.len() + start).saturating_sub(skip);
+        if self.left.len() < length {
+            self.left.resize(length, 0.0);
+            self.right.resize(length, 0.0);
+        }
+        let input = other.left.iter().zip(other.right.iter()).skip(skip);
+        let output = self.left[start..]
+            .iter_mut()
+            .zip(self.right[start..].iter_mut());
+        for ((ol, or), (il, ir)) in output.zip(input) {
+            *ol += gf.l_to_l * il + gf.r_to_l * ir;
+            *or += gf.l_to_r * il + gf.r_to_r * ir;
+        }
+    }
+}
+
+/// A group of tracks that are mixed together first, then mixed into the output as one, with
+/// the gain, pan and reverb of the `[bus.<name>]` table.
+pub struct MixBus {
+    pub gain: f32, // dB
+    pub pan: f32,  // -1 .. 1
+    pub reverb: Option<MixReverb>,
+    pub output: usize, // channel pair
+}
+
+impl MixBus {
+    pub fn from_config(
+        config: &toml::Table,
+    ) -> Result<std::collections::HashMap<String, Self>, String> {
+        let Some(buses) = config.get("bus") else {
+            return Ok(Default::default());
+        };
+        let buses = buses.as_table().ok_or("bus must be a table")?;
+        buses
+            .iter()
+            .map(|(name, bus)| {
+                let bus = bus
+                    .as_table()
+                    .ok_or_else(|| format!("bus {} must be a table", name))?;
+                Ok((
+                    name.clone(),
+                    Self {
+                        gain: bus.get("gain").and_then(toml_float).unwrap_or(0.0),
+                        pan: bus.get("pan").and_then(toml_float).unwrap_or(0.0),
+                        reverb: MixReverb::from_toml(bus)
+                            .map_err(|e| format!("{} of bus {}", e, name))?,
+                        output: output_pair(bus.get("output"))
+                            .map_err(|e| format!("{} for bus {}", e, name))?,
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Mixes the tracks of each bus into a single track, leaving tracks without a bus as they are.
+pub fn mix_buses(
+    tracks: Vec<MixerTrack>,
+    buses: &std::collections::HashMap<String, MixBus>,
+    sr: usize,
+    precision: MixPrecision,
+) -> Vec<MixerTrack> {
+    let mut groups: Vec<(String, Vec<MixerTrack>)> = Vec::new();
+    let mut out = Vec::new();
+    for track in tracks {
+        match track.bus.clone() {
+            Some(name) => match groups.iter_mut().find(|g| g.0 == name) {
+                Some(group) => group.1.push(track),
+                None => groups.push((name, vec![track])),
+            },
+            None => out.push(track),
+        }
+    }
+
+    for (name, tracks) in groups {
+        let mut wavdata = Mixer {
+            tracks,
+            precision,
+            channels: 2,
+        }
+        .mix(&());
+        let bus = buses.get(&name);
+        if let Some(reverb) = bus.and_then(|b| b.reverb.as_ref()) {
+            reverb.apply(&mut wavdata, sr);
+        }
+
+        out.push(MixerTrack {
+            name: format!("{} (bus)", name),
+            track: None,
+            left: wavdata.iter().step_by(2).copied().collect(),
+            right: wavdata.iter().skip(1).step_by(2).copied().collect(),
+            // The pan law already attenuated the bus's tracks by 3dB at the center, compensate
+            // so that routing tracks through a bus doesn't change their level
+            gainfactors: MixerGainFactors::new(
+                bus.map_or(0.0, |b| b.gain) + 3.0103,
+                bus.map_or(0.0, |b| b.pan),
+            ),
+            offset: 0,
+            output: bus.map_or(0, |b| b.output),
+            bus: None,
+        });
+    }
+
+    out
+}
+
+/// A track mixed into the output block by block, either rendered as its samples are needed
+/// or taken from a buffer.
+pub struct StreamTrack {
+    pub source: Option<StreamSource>,
+    pub left: Vec<f32>, // the current block, or the whole buffer
+    pub right: Vec<f32>,
+    pub read: usize,   // position in the current block
+    pub length: usize, // samples
+    pub done: usize,   // samples mixed or skipped
+    pub gainfactors: MixerGainFactors,
+    pub offset: isize, // samples
+}
+
+/// Where the blocks of a streamed track come from: the synthesizer, or the tracks of a bus.
+pub enum StreamSource {
+    Synth(Box<StreamSynth>),
+    Bus {
+        tracks: Vec<StreamTrack>,
+        reverb: Option<ReverbTail>,
+        double: bool, // summed in double precision
+        mixed: usize, // samples
+    },
+}
+
+/// A track rendered block by block, with the effects of the instrument that need no more than a
+/// window of lookahead.
+pub struct StreamSynth {
+    pub renderer: Renderer,
+    pub stream: RenderStream,
+    pub instr: InstrumentSetting,
+    pub trim: Option<f32>,
+    pub delay: Option<[TempoDelay; 2]>,
+    pub gate: Option<StreamGate>,
+}
+
+impl StreamSource {
+    /// Produces the next samples of the track, at least one.
+    pub fn next(&mut self, left: &mut Vec<f32>, right: &mut Vec<f32>, length: usize) {
+        match self {
+            Self::Synth(synth) => {
+                let StreamSynth {
+                    renderer,
+                    stream,
+                    instr,
+                    trim,
+                    delay,
+                    gate,
+                } = synth.as_mut();
+                // The gate may hold back a whole block, so render until samples come out
+                while left.is_empty() {
+                    let n = (stream.sc - stream.si).min(renderer.synth.get_block_size());
+                    left.resize(n, 0.0);
+                    right.resize(n, 0.0);
+                    if n > 0 {
+                        renderer.render_block(stream, instr, left, right);
+                    }
+                    if let Some(trim) = trim {
+                        left.iter_mut()
+                            .chain(right.iter_mut())
+                            .for_each(|s| *s *= *trim);
+                    }
+                    if let Some([delay_l, delay_r]) = delay {
+                        delay_l.process(left);
+                        delay_r.process(right);
+                    }
+                    if let Some(gate) = gate {
+                        gate.process(left, right, stream.si == stream.sc);
+                    }
+                    shape_image(left, right, instr);
+                }
+            }
+            Self::Bus {
+                tracks,
+                reverb,
+                double,
+                mixed,
+            } => {
+                const BLOCK: usize = 4096; // frames
+
+                let n = (length - *mixed).min(BLOCK);
+                let mut block = match double {
+                    false => mix_block::<f32>(tracks, n, *mixed),
+                    true => mix_block::<f64>(tracks, n, *mixed)
+                        .into_iter()
+                        .map(|s| s as f32)
+                        .collect(),
+                };
+                if let Some(reverb) = reverb {
+                    reverb.process(&mut block);
+                }
+                left.extend(block.iter().step_by(2));
+                right.extend(block.iter().skip(1).step_by(2));
+                *mixed += n;
+            }
+        }
+    }
+}
+
+/// Mixes `frames` frames of the tracks, starting at `start`, into an interleaved stereo block.
+pub fn mix_block<T>(tracks: &mut [StreamTrack], frames: usize, start: usize) -> Vec<T>
+where
+    T: Copy + Default + From<f32> + std::ops::AddAssign,
+{
+    let mut block = vec![T::default(); frames * 2];
+    for t in tracks.iter_mut() {
+        t.mix_into(&mut block, start);
+    }
+    block
+}
+
+impl StreamTrack {
+    pub fn synth(
+        mut renderer: Renderer,
+        instr: InstrumentSetting,
+        padding: usize,
+        limit: Option<usize>,
+        gainfactors: MixerGainFactors,
+        offset: isize,
+    ) -> Self {
+        let sr = renderer.synth.get_sample_rate() as usize;
+        let trim = renderer.trim(&instr);
+        let delay = renderer.delay(&instr).map(|delay| [delay.clone(), delay]);
+        let gate = instr.gate_db.map(|threshold| StreamGate {
+            gate: NoiseGate::new(threshold, sr),
+            gain: 0.0,
+            held: (Vec::new(), Vec::new()),
+        });
+        let stream = renderer.start(&instr, padding, limit);
+        Self {
+            length: stream.sc,
+            source: Some(StreamSource::Synth(Box::new(StreamSynth {
+                renderer,
+                stream,
+                instr,
+                trim,
+                delay,
+                gate,
+            }))),
+            left: Vec::new(),
+            right: Vec::new(),
+            read: 0,
+            done: 0,
+            gainfactors,
+            offset,
+        }
+    }
+
+    pub fn buffer(
+        left: Vec<f32>,
+        right: Vec<f32>,
+        gainfactors: MixerGainFactors,
+        offset: isize,
+    ) -> Self {
+        Self {
+            source: None,
+            length: left.len(),
+            left,
+            right,
+            read: 0,
+            done: 0,
+            gainfactors,
+            offset,
+        }
+    }
+
+    /// Mixes the tracks of a bus as they're needed, as `mix_buses` mixes them in full.
+    pub fn bus(
+        tracks: Vec<StreamTrack>,
+        bus: Option<&MixBus>,
+        sr: usize,
+        precision: MixPrecision,
+    ) -> Self {
+        Self {
+            length: tracks
+                .iter()
+                .map(|t| (t.length as isize + t.offset).max(0) as usize)
+                .max()
+                .unwrap_or(0),
+            source: Some(StreamSource::Bus {
+                double: precision.double(tracks.len()),
+                tracks,
+                reverb: bus.and_then(|b| b.reverb.as_ref()).map(|r| r.start(sr)),
+                mixed: 0,
+            }),
+            left: Vec::new(),
+            right: Vec::new(),
+            read: 0,
+            done: 0,
+            gainfactors: MixerGainFactors::new(
+                bus.map_or(0.0, |b| b.gain) + 3.0103,
+                bus.map_or(0.0, |b| b.pan),
+            ),
+            offset: 0,
+        }
+    }
+
+    /// Adds the track to the interleaved block of output frames starting at `start`.
+    pub fn mix_into<T>(&mut self, out: &mut [T], start: usize)
+    where
+        T: Copy + From<f32> + std::ops::AddAssign,
+    {
+        let first = (start as isize).max(self.offset);
+        let last = ((start + out.len() / 2) as isize).min(self.offset + self.length as isize);
+        if first >= last {
+            return;
+        }
+
+        // Tracks with a negative offset start part-way
+        let skip = (first - self.offset) as usize - self.done;
+        self.pull(skip, |_, _, _| {});
+
+        let gf = self.gainfactors.clone();
+        let out = &mut out[(first as usize - start) * 2..];
+        self.pull((last - first) as usize, |i, il, ir| {
+            out[i * 2] += T::from(gf.l_to_l * il + gf.r_to_l * ir);
+            out[i * 2 + 1] += T::from(gf.l_to_r * il + gf.r_to_r * ir);
+        });
+    }
+
+    /// Passes the next `count` samples of the track to `f`, rendering blocks as needed.
+    pub fn pull(&mut self, count: usize, mut f: impl FnMut(usize, f32, f32)) {
+        let mut i = 0;
+        while i < count {
+            if self.read == self.left.len() {
+                self.left.clear();
+                self.right.clear();
+                self.source
+                    .as_mut()
+                    .expect("buffered tracks hold all their samples")
+                    .next(&mut self.left, &mut self.right, self.length);
+                self.read = 0;
+            }
+            let n = (self.left.len() - self.read).min(count - i);
+            for j in 0..n {
+                f(i + j, self.left[self.read + j], self.right[self.read + j]);
+            }
+            self.read += n;
+            self.done += n;
+            i += n;
+        }
+    }
+}
+
+/// Renders the tracks together block by block and sends each mixed block through a bounded
+/// channel, which a separate thread drains into the output. Only a block of each track is held
+/// at a time, and rendering waits whenever the channel is full. The samples are the same as
+/// those of rendering each track in full and mixing them.
+pub fn stream_mix(
+    mut tracks: Vec<StreamTrack>,
+    precision: MixPrecision,
+    progress: &dyn Progress,
+) -> Vec<f32> {
+    const BLOCK: usize = 4096; // frames
+    const QUEUE: usize = 16; // blocks
+
+    let frames = tracks
+        .iter()
+        .map(|t| (t.length as isize + t.offset).max(0) as usize)
+        .max()
+        .unwrap_or(0);
+
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<Vec<f32>>(QUEUE);
+    let consumer = std::thread::spawn(move || {
+        let mut out = Vec::with_capacity(frames * 2);
+        for block in receiver {
+            out.extend_from_slice(&block);
+        }
+        out
+    });
+
+    let double = precision.double(tracks.len());
+    progress.update(Stage::Rendering, 0, frames as u64);
+    for start in (0..frames).step_by(BLOCK) {
+        let n = (frames - start).min(BLOCK);
+        let block = match double {
+            false => mix_block::<f32>(&mut tracks, n, start),
+            true => mix_block::<f64>(&mut tracks, n, start)
+                .into_iter()
+                .map(|s| s as f32)
+                .collect(),
+        };
+        // The consumer only stops once the sender is dropped
+        sender.send(block).unwrap();
+        progress.update(
+            Stage::Rendering,
+            (start + BLOCK).min(frames) as u64,
+            frames as u64,
+        );
+    }
+    drop(sender);
+
+    consumer.join().unwrap()
+}
+
+/// The precision the tracks are summed in, as set by `mix_precision`. By default, mixes of more
+/// than 32 tracks are summed in double precision, where rounding errors start to add up.
+#[derive(Clone, Copy, Default)]
+pub enum MixPrecision {
+    #[default]
+    Auto,
+    Single,
+    Double,
+}
+
+impl MixPrecision {
+    pub fn from_toml(config: &toml::Table) -> Result<Self, String> {
+        match config.get("mix_precision").map(|v| v.as_str()) {
+            None | Some(Some("auto")) => Ok(Self::Auto),
+            Some(Some("f32")) => Ok(Self::Single),
+            Some(Some("f64")) => Ok(Self::Double),
+            Some(_) => Err("mix_precision must be one of auto, f32, f64".into()),
+        }
+    }
+
+    pub fn double(self, tracks: usize) -> bool {
+        match self {
+            Self::Auto => tracks > 32,
+            Self::Single => false,
+            Self::Double => true,
+        }
+    }
+}
+
+/// Sums tracks sample by sample. Every synthesizer is created at the output rate, and impulse
+/// responses are resampled on loading, so the tracks never need resampling here.
+pub struct Mixer {
+    pub tracks: Vec<MixerTrack>,
+    pub precision: MixPrecision,
+    pub channels: usize, // two per channel pair the tracks are routed to
+}
+
+impl Mixer {
+    /// Returns the interleaved mix of the tracks, each in its channel pair.
+    pub fn mix(&self, progress: &dyn Progress) -> Vec<f32> {
+        match self.precision.double(self.tracks.len()) {
+            false => self.sum::<f32>(progress),
+            // Each track is still scaled in single precision, only the sum is kept in double
+            true => self
+                .sum::<f64>(progress)
+                .into_iter()
+                .map(|s| s as f32)
+                .collect(),
+        }
+    }
+
+    pub fn sum<T>(&self, progress: &dyn Progress) -> Vec<T>
+    where
+        T: Copy + Default + From<f32> + std::ops::AddAssign,
+    {
+        let sc = self
+            .tracks
+            .iter()
+            .map(|t| (t.left.len() as isize + t.offset).max(0) as usize)
+            .max()
+            .unwrap();
+
+        let ch = self.channels;
+        let mut out: Vec<T> = vec![T::default(); sc * ch];
+
+        let total = self.tracks.iter().map(|t| t.left.len() as u64).sum();
+        let mut done = 0;
+        progress.update(Stage::Mixing, done, total);
+
+        // Sum the tracks one at a time, so that each is read sequentially
+        for t in self.tracks.iter() {
+            let gf = &t.gainfactors;
+            // Tracks with a negative offset start part-way, tracks with a positive offset later
+            let skip = usize::try_from(-t.offset).unwrap_or(0);
+            let start = usize::try_from(t.offset).unwrap_or(0);
+            let input = t.left.iter().zip(t.right.iter()).skip(skip);
+            let (l, r) = (2 * t.output, 2 * t.output + 1);
+            for (o, (il, ir)) in out[start * ch..].chunks_exact_mut(ch).zip(input) {
+                o[l] += T::from(gf.l_to_l * il + gf.r_to_l * ir);
+                o[r] += T::from(gf.l_to_r * il + gf.r_to_r * ir);
+            }
+            done += t.left.len() as u64;
+            progress.update(Stage::Mixing, done, total);
+        }
+
+        out
+    }
+
+    /// Attributes every clipping sample of the mixed output to the track contributing most to
+    /// it. Returns the offending tracks, worst first, with the number of clipping samples, the
+    /// peak level (in dBFS) and the index of the peak.
+    pub fn clipping(&self, out: &[f32]) -> Vec<(&str, usize, f32, usize)> {
+        let mut offenders: Vec<(&str, usize, f32, usize)> = Vec::new();
+
+        for (si, frame) in out.chunks_exact(self.channels).enumerate() {
+            let (pair, peak) = frame
+                .chunks_exact(2)
+                .map(|s| s[0].abs().max(s[1].abs()))
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            if peak <= 1.0 {
+                continue;
+            }
+
+            let contribution = |t: &MixerTrack| {
+                let gf = &t.gainfactors;
+                let ti = usize::try_from(si as isize - t.offset).unwrap_or(usize::MAX);
+                let il = t.left.get(ti).copied().unwrap_or(0.0);
+                let ir = t.right.get(ti).copied().unwrap_or(0.0);
+                (gf.l_to_l * il + gf.r_to_l * ir)
+                    .abs()
+                    .max((gf.l_to_r * il + gf.r_to_r * ir).abs())
+            };
+            let Some(track) = self
+                .tracks
+                .iter()
+                .filter(|t| t.output == pair)
+                .max_by(|a, b| contribution(a).total_cmp(&contribution(b)))
+            else {
+                continue;
+            };
+
+            let level = 20.0 * peak.log10();
+            match offenders.iter_mut().find(|o| o.0 == track.name) {
+                Some(o) => {
+                    o.1 += 1;
+                    if level > o.2 {
+                        (o.2, o.3) = (level, si);
+                    }
+                }
+                None => offenders.push((&track.name, 1, level, si)),
+            }
+        }
+
+        offenders.sort_by_key(|o| std::cmp::Reverse(o.1));
+        offenders
+    }
+}
+
+/// Channels produced by the mixer, in their native interleaving order.
+pub const MIXER_CHANNELS: [&str; 2] = ["L", "R"];
+
+/// Parses the optional `channel_order` setting into a permutation of the mixer channels.
+pub fn channel_order(config: &toml::Table) -> Result<Vec<usize>, String> {
+    let Some(order) = config.get("channel_order") else {
+        return Ok((0..MIXER_CHANNELS.len()).collect());
+    };
+
+    let order = order
+        .as_array()
+        .ok_or("channel_order must be a list of channel names")?
+        .iter()
+        .map(|v| {
+            let name = v.as_str().unwrap_or_default();
+            MIXER_CHANNELS
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case(name))
+                .ok_or(format!(
+                    "Unknown output channel {} (expected one of {})",
+                    v,
+                    MIXER_CHANNELS.join(", ")
+                ))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    if sorted != (0..MIXER_CHANNELS.len()).collect::<Vec<_>>() {
+        return Err(format!(
+            "channel_order must list each of {} exactly once",
+            MIXER_CHANNELS.join(", ")
+        ));
+    }
+
+    Ok(order)
+}
+
+/// Reorders the channels of interleaved samples, where `order[i]` is the source channel of
+/// output channel `i`.
+pub fn reorder_channels(wavdata: &mut [f32], order: &[usize]) {
+    if order.iter().enumerate().all(|(i, &c)| i == c) {
+        return;
+    }
+    let mut frame = vec![0_f32; order.len()];
+    for chunk in wavdata.chunks_exact_mut(order.len()) {
+        for (dst, &src) in frame.iter_mut().zip(order) {
+            *dst = chunk[src];
+        }
+        chunk.copy_from_slice(&frame);
+    }
+}
+
+/// Freeverb-style reverb for the mix bus, configured by the `[reverb]` table. rustysynth's own
+/// reverb can only be switched on or off, so this is applied to the mixed output instead.
+pub struct MixReverb {
+    pub level: f32,     // 0 .. 1
+    pub room_size: f32, // 0 .. 1
+    pub damping: f32,   // 0 .. 1
+    pub width: f32,     // 0 .. 1
+}
+
+impl MixReverb {
+    pub fn from_toml(config: &toml::Table) -> Result<Option<Self>, String> {
+        let Some(table) = config.get("reverb") else {
+            return Ok(None);
+        };
+        let table = table.as_table().ok_or("reverb must be a table")?;
+        let param = |key: &str, default: f32| match table.get(key).map(toml_float) {
+            None => Ok(default),
+            Some(Some(v)) if (0.0..=1.0).contains(&v) => Ok(v),
+            Some(_) => Err(format!("reverb {} must be between 0 and 1", key)),
+        };
+        Ok(Some(Self {
+            level: param("level", 0.3)?,
+            room_size: param("room_size", 0.5)?,
+            damping: param("damping", 0.5)?,
+            width: param("width", 1.0)?,
+        }))
+    }
+
+    /// Adds the reverb to interleaved stereo samples.
+    pub fn apply(&self, wavdata: &mut [f32], sr: usize) {
+        self.start(sr).process(wavdata);
+    }
+
+    /// Returns the reverb with empty delay lines, to be applied block by block.
+    pub fn start(&self, sr: usize) -> ReverbTail {
+        const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+        const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+        const STEREO_SPREAD: usize = 23;
+
+        // Delay line lengths are tuned for 44.1kHz
+        let scale = |n: usize| (n * sr / 44_100).max(1);
+        let wet = self.level * 3.0;
+
+        ReverbTail {
+            lines: std::array::from_fn(|ch| {
+                let spread = ch * STEREO_SPREAD;
+                let combs: Vec<_> = COMBS
+                    .iter()
+                    .map(|&n| (vec![0_f32; scale(n + spread)], 0_f32))
+                    .collect();
+                let allpasses: Vec<_> = ALLPASSES
+                    .iter()
+                    .map(|&n| vec![0_f32; scale(n + spread)])
+                    .collect();
+                (combs, allpasses)
+            }),
+            feedback: self.room_size * 0.28 + 0.7,
+            damp: self.damping * 0.4,
+            wet1: wet * (self.width / 2.0 + 0.5),
+            wet2: wet * ((1.0 - self.width) / 2.0),
+            frame: 0,
+        }
+    }
+}
+
+/// The comb filters (with the state of their damping) and allpass filters of a reverb channel.
+pub type ReverbLines = (Vec<(Vec<f32>, f32)>, Vec<Vec<f32>>);
+
+/// The delay lines of a reverb being applied block by block.
+pub struct ReverbTail {
+    pub lines: [ReverbLines; 2],
+    pub feedback: f32,
+    pub damp: f32,
+    pub wet1: f32,
+    pub wet2: f32,
+    pub frame: usize, // frames processed
+}
+
+impl ReverbTail {
+    /// Adds the reverb to the next interleaved stereo samples.
+    pub fn process(&mut self, wavdata: &mut [f32]) {
+        let (feedback, damp) = (self.feedback, self.damp);
+        for (i, frame) in wavdata.chunks_exact_mut(2).enumerate() {
+            let i = self.frame + i;
+            let input = (frame[0] + frame[1]) * 0.015;
+
+            let out: [f32; 2] = std::array::from_fn(|ch| {
+                let (combs, allpasses) = &mut self.lines[ch];
+                let mut out = 0_f32;
+                for (buf, store) in combs.iter_mut() {
+                    let idx = i % buf.len();
+                    let y = buf[idx];
+                    *store = y * (1.0 - damp) + *store * damp;
+                    buf[idx] = input + *store * feedback;
+                    out += y;
+                }
+                for buf in allpasses.iter_mut() {
+                    let idx = i % buf.len();
+                    let y = buf[idx];
+                    buf[idx] = out + y * 0.5;
+                    out = y - out;
+                }
+                out
+            });
+
+            frame[0] += out[0] * self.wet1 + out[1] * self.wet2;
+            frame[1] += out[1] * self.wet1 + out[0] * self.wet2;
+        }
+        self.frame += wavdata.len() / 2;
+    }
+}
+
+/// In-place radix-2 FFT of a complex signal, whose length must be a power of two. The inverse
+/// transform is not scaled.
+pub fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let twiddles: Vec<(f32, f32)> = (0..n / 2)
+        .map(|k| {
+            let (im, re) = (sign * 2.0 * std::f64::consts::PI * k as f64 / n as f64).sin_cos();
+            (re as f32, im as f32)
+        })
+        .collect();
+
+    let mut len = 2;
+    while len <= n {
+        let stride = n / len;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = twiddles[k * stride];
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len *= 2;
+    }
+}
+
+/// Convolves a signal with an impulse response using uniformly partitioned FFT convolution,
+/// which keeps the transforms short however long the impulse response is.
+pub fn convolve(input: &[f32], ir: &[f32]) -> Vec<f32> {
+    let block = ir.len().next_power_of_two().clamp(64, 16_384);
+    let n = block * 2;
+    let out_len = input.len() + ir.len() - 1;
+
+    let spectrum = |data: &[f32]| {
+        let mut re = vec![0_f32; n];
+        let mut im = vec![0_f32; n];
+        re[..data.len()].copy_from_slice(data);
+        fft(&mut re, &mut im, false);
+        (re, im)
+    };
+    let partitions: Vec<_> = ir.chunks(block).map(spectrum).collect();
+
+    // Spectra of the most recent input blocks, newest first, one per partition
+    let mut history = std::collections::VecDeque::with_capacity(partitions.len());
+    let mut out = vec![0_f32; out_len.next_multiple_of(block) + block];
+    for k in 0..out_len.div_ceil(block) {
+        let start = (k * block).min(input.len());
+        let end = ((k + 1) * block).min(input.len());
+        if history.len() == partitions.len() {
+            history.pop_back();
+        }
+        history.push_front(spectrum(&input[start..end]));
+
+        let mut re = vec![0_f32; n];
+        let mut im = vec![0_f32; n];
+        for ((x_re, x_im), (h_re, h_im)) in history.iter().zip(partitions.iter()) {
+            for i in 0..n {
+                re[i] += x_re[i] * h_re[i] - x_im[i] * h_im[i];
+                im[i] += x_re[i] * h_im[i] + x_im[i] * h_re[i];
+            }
+        }
+        fft(&mut re, &mut im, true);
+
+        for (o, y) in out[k * block..].iter_mut().zip(re.iter()) {
+            *o += y / n as f32;
+        }
+    }
+
+    out.truncate(out_len);
+    out
+}
+
+/// A second order IIR filter section (direct form I).
+pub struct Biquad {
+    pub b: [f64; 3],
+    pub a: [f64; 2], // normalized, a0 = 1
+    pub x: [f64; 2],
+    pub y: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Returns the RMS level (in dB) of the mono sum of stereo frames relative to that of the
+/// stereo frames: 0 dB for identical channels, -3 dB for a single channel and far lower where
+/// the channels cancel. Returns None for silence.
+pub fn mono_loss(frames: impl Iterator<Item = (f32, f32)>) -> Option<f32> {
+    let (mut stereo, mut mono) = (0_f64, 0_f64);
+    for (l, r) in frames {
+        let (l, r) = (f64::from(l), f64::from(r));
+        stereo += (l * l + r * r) / 2.0;
+        mono += ((l + r) / 2.0).powi(2);
+    }
+    (stereo > 0.0).then(|| (10.0 * (mono / stereo).max(1e-12).log10()) as f32)
+}
+
+/// Returns the mono loss (in dB) of each octave band of interleaved stereo samples, along with
+/// the edges of the band (in Hz), the highest reaching up to the Nyquist frequency. Bands more
+/// than 60 dB below the loudest are left out.
+pub fn mono_band_losses(wavdata: &[f32], sr: usize) -> Vec<(f32, f32, f32)> {
+    const FRAME: usize = 4096;
+    const LOWEST: f32 = 22.0; // Hz, the lower edge of the 31.5 Hz octave band
+    let bands = ((sr as f32 / 2.0 / LOWEST).log2() as usize).max(1);
+    let mut stereo = vec![0_f64; bands];
+    let mut mono = vec![0_f64; bands];
+
+    for chunk in wavdata.chunks(FRAME * 2) {
+        let (mut l_re, mut r_re) = (vec![0_f32; FRAME], vec![0_f32; FRAME]);
+        for (i, frame) in chunk.chunks_exact(2).enumerate() {
+            l_re[i] = frame[0];
+            r_re[i] = frame[1];
+        }
+        let (mut l_im, mut r_im) = (vec![0_f32; FRAME], vec![0_f32; FRAME]);
+        fft(&mut l_re, &mut l_im, false);
+        fft(&mut r_re, &mut r_im, false);
+
+        for k in 1..FRAME / 2 {
+            let f = k as f32 * sr as f32 / FRAME as f32;
+            let Some(band) = Some((f / LOWEST).log2()).filter(|&b| b >= 0.0) else {
+                continue;
+            };
+            let band = (band as usize).min(bands - 1);
+            let power = |re: f32, im: f32| f64::from(re * re + im * im);
+            stereo[band] += (power(l_re[k], l_im[k]) + power(r_re[k], r_im[k])) / 2.0;
+            // The spectrum of the mono sum is the sum of the spectra of the channels
+            mono[band] += power((l_re[k] + r_re[k]) / 2.0, (l_im[k] + r_im[k]) / 2.0);
+        }
+    }
+
+    let loudest = stereo.iter().copied().fold(0.0, f64::max);
+    (0..bands)
+        .filter(|&b| stereo[b] > 0.0 && stereo[b] > loudest * 1e-6)
+        .map(|b| {
+            let loss = 10.0 * (mono[b] / stereo[b]).max(1e-12).log10();
+            let low = LOWEST * (1 << b) as f32;
+            let high = match b + 1 == bands {
+                true => sr as f32 / 2.0,
+                false => low * 2.0,
+            };
+            (low, high, loss as f32)
+        })
+        .collect()
+}
+
+/// Measures the integrated loudness (in LUFS) of interleaved stereo samples following ITU-R
+/// BS.1770: K-weighted, in gated 400ms blocks overlapping by 75%. Returns None for silence, or if
+/// there isn't a single complete block.
+pub fn loudness(wavdata: &[f32], sr: usize) -> Option<f64> {
+    let fs = sr as f64;
+
+    // K-weighting: a high shelf modelling the head, followed by a high-pass. These are the
+    // filters of BS.1770 (specified at 48kHz) derived for the actual sample rate.
+    let k_weighting = || {
+        let k = (std::f64::consts::PI * 1681.974450955533 / fs).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let shelf = Biquad::new(
+            [
+                vh + vb * k / q + k * k,
+                2.0 * (k * k - vh),
+                vh - vb * k / q + k * k,
+            ],
+            [
+                1.0 + k / q + k * k,
+                2.0 * (k * k - 1.0),
+                1.0 - k / q + k * k,
+            ],
+        );
+
+        let k = (std::f64::consts::PI * 38.13547087602444 / fs).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Biquad::new(
+            [a0, -2.0 * a0, a0], // unity gain in the pass band
+            [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+        );
+        [shelf, highpass]
+    };
+
+    // Sum of the squared, weighted samples of both channels per 100ms step. A final partial step
+    // is left out, as blocks must be complete.
+    let step = sr / 10;
+    let mut filters = [k_weighting(), k_weighting()];
+    let steps: Vec<f64> = wavdata
+        .chunks_exact(step * 2)
+        .map(|chunk| {
+            chunk
+                .chunks_exact(2)
+                .map(|frame| {
+                    frame
+                        .iter()
+                        .zip(filters.iter_mut())
+                        .map(|(&s, [shelf, highpass])| {
+                            let y = highpass.process(shelf.process(f64::from(s)));
+                            y * y
+                        })
+                        .sum::<f64>()
+                })
+                .sum()
+        })
+        .collect();
+
+    let blocks: Vec<f64> = steps
+        .windows(4)
+        .map(|w| w.iter().sum::<f64>() / (4 * step) as f64)
+        .collect();
+    let lufs = |power: f64| -0.691 + 10.0 * power.log10();
+    // Every block must pass both the absolute gate of -70 LUFS and the relative gate, if any
+    let gated_mean = |relative: f64| {
+        let gated: Vec<_> = blocks
+            .iter()
+            .filter(|&&p| lufs(p) > -70.0 && lufs(p) > relative)
+            .collect();
+        (!gated.is_empty()).then(|| gated.iter().copied().sum::<f64>() / gated.len() as f64)
+    };
+
+    // The relative gate lies 10 LU below the loudness of the blocks passing the absolute gate
+    let absolute = gated_mean(f64::NEG_INFINITY)?;
+    gated_mean(lufs(absolute) - 10.0).map(lufs)
+}
+
+/// Estimates the true peak level of interleaved stereo samples by 4x oversampling with a
+/// windowed-sinc interpolator.
+pub fn true_peak(wavdata: &[f32]) -> f32 {
+    const PHASES: usize = 4;
+    const TAPS: isize = 8; // on either side
+
+    let kernel: Vec<Vec<f32>> = (0..PHASES)
+        .map(|p| {
+            (-TAPS + 1..=TAPS)
+                .map(|k| {
+                    let x = k as f32 - p as f32 / PHASES as f32;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+                    };
+                    let window = 0.5 + 0.5 * (std::f32::consts::PI * x / TAPS as f32).cos();
+                    sinc * window
+                })
+                .collect()
+        })
+        .collect();
+
+    let frames = wavdata.len() / 2;
+    let mut peak = 0_f32;
+    for ch in 0..2 {
+        let sample = |i: isize| {
+            usize::try_from(i)
+                .ok()
+                .filter(|&i| i < frames)
+                .map_or(0.0, |i| wavdata[i * 2 + ch])
+        };
+        for i in 0..frames as isize {
+            peak = peak.max(sample(i).abs());
+            for taps in kernel.iter().skip(1) {
+                let v: f32 = (-TAPS + 1..=TAPS)
+                    .zip(taps)
+                    .map(|(k, c)| sample(i + k) * c)
+                    .sum();
+                peak = peak.max(v.abs());
+            }
+        }
+    }
+    peak
+}
+
+/// Applies lo-fi effects to interleaved samples: reducing the effective bit depth and holding
+/// samples to reduce the effective sample rate.
+pub fn lofi(wavdata: &mut [f32], channels: usize, bits: Option<u8>, downsample: Option<usize>) {
+    if let Some(factor) = downsample.filter(|&f| f > 1) {
+        for block in wavdata.chunks_mut(channels * factor) {
+            let (first, rest) = block.split_at_mut(channels.min(block.len()));
+            for frame in rest.chunks_mut(channels) {
+                frame.copy_from_slice(&first[..frame.len()]);
+            }
+        }
+    }
+
+    if let Some(bits) = bits {
+        let levels = 2_f32.powi(i32::from(bits.clamp(1, 24)) - 1);
+        for s in wavdata.iter_mut() {
+            *s = (*s * levels).round() / levels;
+        }
+    }
+}
+
+/// Converts a type-0 MIDI file into a type-1 file with one named track per channel. Meta and
+/// system events are kept on a leading conductor track.
+pub fn split_channels<'a>(
+    smf: &midly::Smf<'a>,
+    names: &'a [String],
+) -> Result<midly::Smf<'a>, String> {
+    if smf.header.format != midly::Format::SingleTrack {
+        return Err("Only type-0 MIDI files can be split by channel".into());
+    }
+
+    let mut conductor = Vec::new();
+    let mut channels: Vec<Vec<(u64, midly::TrackEventKind<'a>)>> = vec![Vec::new(); 16];
+    let mut end_ticks = 0;
+
+    for track in smf.tracks.iter() {
+        let mut ticks = 0_u64;
+        for e in track.iter() {
+            ticks += u64::from(e.delta.as_int());
+            end_ticks = end_ticks.max(ticks);
+            match e.kind {
+                midly::TrackEventKind::Midi { channel, .. } => {
+                    channels[usize::from(channel.as_int())].push((ticks, e.kind));
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) => {}
+                kind => conductor.push((ticks, kind)),
+            }
+        }
+    }
+
+    let tracks = std::iter::once(conductor)
+        .chain(
+            channels
+                .into_iter()
+                .enumerate()
+                .filter(|(_, events)| !events.is_empty())
+                .map(|(ch, mut events)| {
+                    let name = midly::MetaMessage::TrackName(names[ch].as_bytes());
+                    events.insert(0, (0, midly::TrackEventKind::Meta(name)));
+                    events
+                }),
+        )
+        .map(|mut events| {
+            events.push((
+                end_ticks,
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            ));
+            let mut last = 0;
+            let mut track = midly::Track::new();
+            for (ticks, kind) in events {
+                // Deltas are 28-bit, so a channel silent for longer is bridged by empty texts
+                let mut delta = ticks - last;
+                while delta > 0x0fff_ffff {
+                    track.push(midly::TrackEvent {
+                        delta: 0x0fff_ffff.into(),
+                        kind: midly::TrackEventKind::Meta(midly::MetaMessage::Text(b"")),
+                    });
+                    delta -= 0x0fff_ffff;
+                }
+                track.push(midly::TrackEvent {
+                    delta: (delta as u32).into(),
+                    kind,
+                });
+                last = ticks;
+            }
+            track
+        })
+        .collect();
+
+    Ok(midly::Smf {
+        header: midly::Header::new(midly::Format::Parallel, smf.header.timing),
+        tracks,
+    })
+}
+
+/// Builds the mapping of track names to instrument settings. A setting applies to the track it
+/// is listed under, or, if it has a `tracks` list, to each of the tracks named there instead.
+pub fn instrument_index(
+    instr: &toml::Table,
+) -> Result<std::collections::HashMap<String, Vec<&toml::Value>>, String> {
+    let mut index: std::collections::HashMap<_, Vec<_>> = std::collections::HashMap::new();
+
+    for (name, settings) in instr {
+        for setting in instrument_list(name, settings)? {
+            match setting.get("tracks").and_then(|v| v.as_array()) {
+                Some(tracks) => {
+                    for track in tracks.iter().filter_map(|t| t.as_str()) {
+                        index.entry(track.to_string()).or_default().push(setting);
+                    }
+                }
+                None => index.entry(name.clone()).or_default().push(setting),
+            }
+        }
+    }
+
+    Ok(index)
+}
+
+/// Checks that the layers of a track crossfading by velocity are a pair with the same range,
+/// and returns the position of the second, louder one among the layers, if any.
+pub fn xfade_pair<'a>(
+    settings: impl IntoIterator<Item = &'a toml::Value>,
+) -> Result<Option<usize>, String> {
+    let layers: Vec<_> = settings
+        .into_iter()
+        .enumerate()
+        .map(|(i, s)| (i, s.get("xfade_lo"), s.get("xfade_hi")))
+        .filter(|&(_, lo, hi)| lo.is_some() || hi.is_some())
+        .collect();
+    match layers[..] {
+        [] => Ok(None),
+        [(_, lo, hi), (upper, upper_lo, upper_hi)] if lo == upper_lo && hi == upper_hi => {
+            Ok(Some(upper))
+        }
+        _ => Err("xfade_lo and xfade_hi must be set alike on a pair of layers".into()),
+    }
+}
+
+/// Matches the tracks to their instrument settings, by track name or else by channel, and
+/// returns the parts to render along with their settings and padding. Problems that only skip
+/// a track are reported through `warn`.
+pub fn plan_renders(
+    instr: &std::collections::HashMap<String, Vec<&toml::Value>>,
+    channels: Option<&toml::Table>,
+    tracks: &[PlayerTrack],
+    sf: &std::sync::Arc<rustysynth::SoundFont>,
+    only: &[String],
+    check_audible: bool,
+    warn: &mut dyn FnMut(String),
+) -> Vec<(String, PlayerTrack, InstrumentSetting, usize)> {
+    let mut renders = Vec::new();
+    let mut audible = std::collections::HashMap::new();
+    let mut jobs = Vec::new();
+
+    let display_names: Vec<_> = tracks
+        .iter()
+        .enumerate()
+        .map(|(idx, t)| t.name.clone().unwrap_or_else(|| format!("Track {}", idx)))
+        .collect();
+
+    for name in only.iter() {
+        if !display_names.contains(name) {
+            warn(format!("Track {} not found in MIDI file!", name));
+        }
+    }
+
+    for (track, display_name) in tracks.iter().zip(display_names) {
+        if !only.is_empty() && !only.contains(&display_name) {
+            continue;
+        }
+
+        if let Some(settings) = track.name.as_ref().and_then(|n| instr.get(n)) {
+            if track.events.is_empty() {
+                warn(format!("No notes in {}, skipping track!", display_name));
+            } else {
+                jobs.push((display_name, track.clone(), settings.clone()));
+            }
+            continue;
+        }
+
+        let mut matched = false;
+        if let Some(channels) = channels {
+            for (ch, part) in track.split_by_channel() {
+                let Some(settings) = channels.get(&(ch + 1).to_string()) else {
+                    continue;
+                };
+                let part_name = format!("{} [ch {}]", display_name, ch + 1);
+                match instrument_list(&format!("channel {}", ch + 1), settings) {
+                    Ok(settings) => jobs.push((part_name, part, settings.iter().collect())),
+                    Err(msg) => warn(format!("{msg}, skipping {}!", part_name)),
+                }
+                matched = true;
+            }
+        }
+
+        // Tracks without notes, such as the conductor track, need no instruments
+        if !matched && !track.events.is_empty() {
+            match track.name {
+                Some(_) => warn(format!(
+                    "No instruments defined for {}, skipping track!",
+                    display_name
+                )),
+                None => warn(format!(
+                    "{} has no name and no instrument mapping, skipping track!",
+                    display_name
+                )),
+            }
+        }
+    }
+
+    for (track_name, track, settings) in jobs {
+        let upper = match xfade_pair(settings.iter().copied()) {
+            Ok(upper) => upper,
+            Err(msg) => {
+                warn(format!("{msg} for {}, skipping track!", track_name));
+                continue;
+            }
+        };
+        for (i, setting) in settings.into_iter().enumerate() {
+            let mut is = match InstrumentSetting::from_toml(setting) {
+                Ok(is) => is,
+                Err(msg) => {
+                    warn(format!("{msg} for {}, skipping track!", track_name));
+                    continue;
+                }
+            };
+            is.xfade_up = upper == Some(i);
+
+            // Without a configured bank or preset name, use the bank the MIDI file selects
+            if setting.get("bank").is_none()
+                && is.preset_name.is_none()
+                && let Some(bank) = track.bank
+            {
+                is.bank = bank;
+            }
+            if let Err(msg) = is.resolve_preset_name(sf, setting.get("bank").is_some()) {
+                warn(format!("{msg} for {}, skipping track!", track_name));
+                continue;
+            }
+
+            if check_audible
+                && !*audible
+                    .entry((is.bank, is.preset))
+                    .or_insert_with(|| preset_is_audible(sf, is.bank, is.preset))
+            {
+                warn(format!(
+                    "Preset {}:{} for {} renders silence, check the soundfont!",
+                    is.bank, is.preset, track_name
+                ));
+            }
+
+            // Render each channel separately with the same settings, if requested
+            let parts = if is.per_channel {
+                track
+                    .split_by_channel()
+                    .into_iter()
+                    .map(|(ch, t)| (format!("{} [ch {}]", track_name, ch + 1), t))
+                    .collect()
+            } else {
+                vec![(track_name.clone(), track.clone())]
+            };
+
+            for (part_name, part) in parts {
+                renders.push((part_name, part, is.clone(), release_padding(sf, &is)));
+            }
+        }
+    }
+
+    renders
+}
+
+/// Mutes the configured ranges of the planned parts, converting bars to times with the time
+/// map. Ranges past the end of a part are reported through `warn`.
+pub fn apply_mutes(
+    renders: &mut [(String, PlayerTrack, InstrumentSetting, usize)],
+    timemap: &TimeMap,
+    warn: &mut dyn FnMut(String),
+) {
+    for (part_name, part, is, _) in renders.iter_mut() {
+        for (first, last) in std::mem::take(&mut is.mute_bars) {
+            let Some(start) = timemap.bar_start(first) else {
+                warn(format!(
+                    "Muted bar {} of {} is past the end of the song",
+                    first, part_name
+                ));
+                continue;
+            };
+            // Up to the start of the bar after the range, or else to the end
+            let end = timemap.bar_start(last + 1).unwrap_or(usize::MAX);
+            is.mute.push((start, end));
+        }
+        for &(start, _) in is.mute.iter() {
+            if start >= part.length {
+                warn(format!(
+                    "Muted range from {:.1}s of {} is past the end of the track",
+                    start as f64 / 1_000_000.0,
+                    part_name
+                ));
+            }
+        }
+        part.mute(&is.mute);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test soundfont: a looped 441 Hz sine, under presets 0, 6 and 40 to 43 of bank 0 and
+    /// preset 0 of the percussion bank.
+    fn soundfont() -> std::sync::Arc<rustysynth::SoundFont> {
+        let mut data: &[u8] = include_bytes!("../tests/data/sine.sf2");
+        std::sync::Arc::new(rustysynth::SoundFont::new(&mut data).unwrap())
+    }
+
+    fn note_on(key: u8, vel: u8) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Midi {
+            channel: 0.into(),
+            message: midly::MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        }
+    }
+
+    fn note_off(key: u8) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Midi {
+            channel: 0.into(),
+            message: midly::MidiMessage::NoteOff {
+                key: key.into(),
+                vel: 64.into(),
+            },
+        }
+    }
+
+    fn tempo(us_per_beat: u32) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(us_per_beat.into()))
+    }
+
+    fn track_name(name: &'static [u8]) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(name))
+    }
+
+    /// Builds a track from events and the ticks before each of them.
+    fn track(events: &[(u32, midly::TrackEventKind<'static>)]) -> midly::Track<'static> {
+        events
+            .iter()
+            .map(|&(delta, kind)| midly::TrackEvent {
+                delta: delta.into(),
+                kind,
+            })
+            .collect()
+    }
+
+    /// A track playing the given notes, each half a beat long and a beat apart.
+    fn melody(name: &'static [u8], keys: &[u8]) -> midly::Track<'static> {
+        let mut events = vec![(0, track_name(name))];
+        for (i, &key) in keys.iter().enumerate() {
+            let rest = if i == 0 { 0 } else { 240 };
+            events.extend([(rest, note_on(key, 100)), (240, note_off(key))]);
+        }
+        track(&events)
+    }
+
+    /// Writes the tracks to a MIDI file at 480 ticks per beat.
+    fn smf(tracks: Vec<midly::Track<'static>>) -> Vec<u8> {
+        let format = match tracks.len() {
+            1 => midly::Format::SingleTrack,
+            _ => midly::Format::Parallel,
+        };
+        let timing = midly::Timing::Metrical(480.into());
+        let mut data = Vec::new();
+        midly::Smf {
+            header: midly::Header::new(format, timing),
+            tracks,
+        }
+        .write_std(&mut data)
+        .unwrap();
+        data
+    }
+
+    /// Sequences the tracks at 480 ticks per beat.
+    fn sequence(tracks: Vec<midly::Track<'static>>) -> (Vec<PlayerTrack>, TimeMap) {
+        let mut seq = Sequencer {
+            tracks: tracks.into_iter().map(SequencedTrack::create).collect(),
+            timemap: TimeMap::default(),
+        };
+        let timing = midly::Timing::Metrical(480.into());
+        let tracks = seq.play_all(timing, false, &()).unwrap();
+        (tracks, seq.timemap)
+    }
+
+    fn renderer(track: PlayerTrack, sr: usize) -> Renderer {
+        let settings = RenderQuality::default().settings(sr as i32, None);
+        Renderer {
+            synth: rustysynth::Synthesizer::new(&soundfont(), &settings).unwrap(),
+            track,
+            tuning: 0.0,
+            scale: None,
+            stolen: (0, 0),
+        }
+    }
+
+    /// A stereo sine of the given frequency and amplitude, in both channels.
+    fn sine(freq: f32, amplitude: f32, sr: usize, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = amplitude * (std::f32::consts::TAU * freq * i as f32 / sr as f32).sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loudness_of_sine() {
+        // A 997 Hz sine in both channels reads as loud as its level in dBFS
+        let lufs = loudness(&sine(997.0, 0.1, 48000, 48000 * 3), 48000).unwrap();
+        assert!((lufs + 20.0).abs() < 0.05, "{}", lufs);
+        assert_eq!(loudness(&vec![0.0; 2 * 48000], 48000), None);
+        assert_eq!(loudness(&sine(997.0, 0.1, 48000, 48000 / 3), 48000), None);
+    }
+
+    #[test]
+    fn loudness_ignores_partial_block() {
+        let full = loudness(&sine(997.0, 0.1, 48000, 48000), 48000).unwrap();
+        let partial = loudness(&sine(997.0, 0.1, 48000, 48000 * 21 / 20), 48000).unwrap();
+        assert!((full - partial).abs() < 1e-3, "{} != {}", full, partial);
+    }
+
+    #[test]
+    fn loudness_gates_absolutely() {
+        // The quiet half lies above the relative gate, but below the absolute one
+        let amplitude = |lufs: f32| 10f32.powf(lufs / 20.0);
+        let mut wavdata = sine(997.0, amplitude(-62.0), 48000, 48000 * 2);
+        wavdata.extend(sine(997.0, amplitude(-71.0), 48000, 48000 * 2));
+        // Blocks overlapping both halves still pass, but none of the quiet half alone
+        let lufs = loudness(&wavdata, 48000).unwrap();
+        assert!((lufs + 62.0).abs() < 0.5, "{}", lufs);
+    }
+
+    #[test]
+    fn smpte_timing_is_an_error() {
+        let timing = midly::Timing::Timecode(midly::Fps::Fps25, 40);
+        let mut seq = Sequencer {
+            tracks: Vec::new(),
+            timemap: TimeMap::default(),
+        };
+        assert_eq!(
+            seq.play_all(timing, false, &()).err().as_deref(),
+            Some("SMPTE timing not supported")
+        );
+    }
+
+    #[test]
+    fn simultaneous_events_keep_order() {
+        // Eight tracks each striking 100 keys at once and releasing them a beat later
+        let keys: Vec<u8> = (0..100).map(|i| (i * 37 % 128) as u8).collect();
+        let file: Vec<_> = (0..8)
+            .map(|_| {
+                let mut events: Vec<_> = keys.iter().map(|&k| (0, note_on(k, 100))).collect();
+                events.extend(keys.iter().enumerate().map(|(i, &k)| {
+                    let rest = if i == 0 { 480 } else { 0 };
+                    (rest, note_off(k))
+                }));
+                track(&events)
+            })
+            .collect();
+
+        // The merged stream plays the tracks in order at the same ticks, each in file order
+        let mut seq = Sequencer {
+            tracks: file.iter().cloned().map(SequencedTrack::create).collect(),
+            timemap: TimeMap::default(),
+        };
+        let mut played = Vec::new();
+        while let Some(e) = seq.next() {
+            played.push((e.ticks, e.idx));
+        }
+        let mut expected: Vec<_> = [0, 480]
+            .into_iter()
+            .flat_map(|ticks| (0..8).flat_map(move |idx| [(ticks, idx); 100]))
+            .collect();
+        expected.sort();
+        assert_eq!(played, expected);
+
+        let (tracks, _) = sequence(file);
+        for t in tracks {
+            let notes: Vec<_> = t.events.iter().map(|e| e.note).collect();
+            assert_eq!(notes, keys);
+            assert!(
+                t.events
+                    .iter()
+                    .all(|e| e.time == 0 && e.duration == 500_000)
+            );
+        }
+    }
+
+    #[test]
+    fn long_pulse_keeps_time() {
+        // Two hours of sixteenths at 180 BPM, 83333.25 µs apart, restating the tempo on each
+        // one as some exporters do, so that every pulse starts a new tempo segment
+        const PULSES: usize = 86_400;
+        let mut events = Vec::new();
+        for i in 0..PULSES {
+            let rest = if i == 0 { 0 } else { 60 };
+            events.extend([
+                (rest, tempo(333_333)),
+                (0, note_on(60, 100)),
+                (60, note_off(60)),
+            ]);
+        }
+        let (tracks, _) = sequence(vec![track(&events)]);
+
+        let last = tracks[0].events.last().unwrap();
+        let ideal = (PULSES - 1) as f64 * 120.0 * 333_333.0 / 480.0;
+        let error = (last.time as f64 - ideal).abs() * crate::SAMPLE_RATE as f64 / 1e6;
+        assert!(error <= 1.0, "off by {} samples", error);
+    }
+
+    #[test]
+    fn stream_matches_batch() {
+        let (tracks, _) = sequence(vec![
+            track(&[(0, tempo(400_000))]),
+            melody(b"upper", &[72, 76, 79, 84]),
+            melody(b"lower", &[48, 55, 52, 60]),
+            melody(b"bass", &[36, 43]),
+        ]);
+        let config: toml::Table = r#"
+            [bus.keys]
+            gain = -2
+            pan = 0.3
+            reverb = { level = 0.4 }
+        "#
+        .parse()
+        .unwrap();
+        let buses = MixBus::from_config(&config).unwrap();
+        // Delays, gates and levels on tracks of a bus, with an offset, and on one without
+        let settings = [
+            "preset = 0\ndelay_beats = 0.75\ngate_db = -30\nlevel = -12\nbus = \"keys\"",
+            "preset = 0\ndelay_beats = 0.5\npan = -0.5\noffset_ms = 120\nbus = \"keys\"",
+            "preset = 40\ngate_db = -20\nwidth = 0.5\ndelay_beats = 1",
+        ];
+        let instruments = || {
+            tracks[1..]
+                .iter()
+                .zip(settings)
+                .map(|(track, setting)| (track.clone(), instrument(setting).unwrap()))
+        };
+        let sr = 44100;
+
+        let mtracks = instruments()
+            .map(|(track, is)| {
+                let (left, right) = renderer(track.clone(), sr).render(&is, PADDING, None, &());
+                MixerTrack {
+                    name: String::new(),
+                    track: track.name.clone(),
+                    left,
+                    right,
+                    gainfactors: MixerGainFactors::from_setting(&is, &track),
+                    offset: is.offset(sr),
+                    output: 0,
+                    bus: is.bus.clone(),
+                }
+            })
+            .collect();
+        let mixer = Mixer {
+            tracks: mix_buses(mtracks, &buses, sr, MixPrecision::Auto),
+            precision: MixPrecision::Auto,
+            channels: 2,
+        };
+        let batch = mixer.mix(&());
+
+        let mut stracks = Vec::new();
+        let mut bus = Vec::new();
+        for (track, is) in instruments() {
+            let gainfactors = MixerGainFactors::from_setting(&is, &track);
+            let strack = StreamTrack::synth(
+                renderer(track, sr),
+                is.clone(),
+                PADDING,
+                None,
+                gainfactors,
+                0,
+            );
+            let strack = StreamTrack {
+                offset: is.offset(sr),
+                ..strack
+            };
+            match is.bus {
+                Some(_) => bus.push(strack),
+                None => stracks.push(strack),
+            }
+        }
+        stracks.push(StreamTrack::bus(
+            bus,
+            buses.get("keys"),
+            sr,
+            MixPrecision::Auto,
+        ));
+        let streamed = stream_mix(stracks, MixPrecision::Auto, &());
+
+        assert_eq!(batch.len(), streamed.len());
+        assert!(batch.iter().any(|&s| s != 0.0));
+        assert!(batch == streamed);
+    }
+
+    fn instrument(toml: &str) -> Result<InstrumentSetting, String> {
+        InstrumentSetting::from_toml(&toml::Value::Table(toml.parse().unwrap()))
+    }
+
+    #[test]
+    fn render_ends_in_partial_block() {
+        // A note held to the end of a 1001-tick track, 50049 samples at 48 kHz: 782 blocks of 64
+        // and one sample more
+        let (tracks, _) = sequence(vec![track(&[
+            (0, note_on(60, 100)),
+            (
+                1001,
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            ),
+        ])]);
+        let is = instrument("preset = 0").unwrap();
+        let sr = 48000;
+        let bs = renderer(tracks[0].clone(), sr).synth.get_block_size();
+        let frames = tracks[0].length * sr / 1_000_000;
+        assert_ne!(frames % bs, 0);
+
+        let (left, right) = renderer(tracks[0].clone(), sr).render(&is, 0, None, &());
+        assert_eq!((left.len(), right.len()), (frames, frames));
+
+        // The tail is rendered as part of a longer render would be
+        let padding = 10_000; // µs
+        let (longer, _) = renderer(tracks[0].clone(), sr).render(&is, padding, None, &());
+        assert_eq!(left[..], longer[..frames]);
+        assert!(left[frames - 16..].iter().any(|s| s.abs() > 0.01));
+    }
+
+    #[test]
+    fn instrument_setting_types() {
+        let instr =
+            instrument("preset = 40\ntsp = -12\ngain = -3\npan = 0.5\ninvert = true\n").unwrap();
+        assert_eq!(instr.preset, 40);
+        assert_eq!(instr.transpose, Some(-12));
+        assert_eq!((instr.gain, instr.pan), (Some(-3.0), Some(0.5)));
+        assert!(instr.invert && !instr.per_channel);
+
+        // Values of the wrong type are errors rather than ignored, and out of range values
+        // aren't wrapped around
+        for (toml, error) in [
+            (
+                "preset = 0\ngain = \"x\"",
+                "gain must be a number, not a string",
+            ),
+            (
+                "preset = 0\nrelease = [1]",
+                "release must be a number, not an array",
+            ),
+            (
+                "preset = 0\ninvert = 1",
+                "invert must be true or false, not an integer",
+            ),
+            (
+                "preset = 0\nbus = 1",
+                "bus must be a string, not an integer",
+            ),
+            (
+                "preset = 0\nbank = 1.5",
+                "bank must be an integer, not a float",
+            ),
+            (
+                "preset = 0\ntsp = 200",
+                "tsp must be from -127 to 127 semitones",
+            ),
+            (
+                "preset = 0\nmax_len_ms = -1",
+                "max_len_ms must not be negative",
+            ),
+            ("preset = 300", "preset must be from 0 to 127"),
+            ("preset = \"40\"", "preset must be an integer, not a string"),
+        ] {
+            assert_eq!(instrument(toml).err().as_deref(), Some(error), "{}", toml);
+        }
+    }
+
+    /// Plans the renders of the tracks with the instruments of the configuration, returning
+    /// the names of the parts to render and the warnings.
+    fn plan(tracks: &[PlayerTrack], config: &str) -> (Vec<String>, Vec<String>) {
+        let config: toml::Table = config.parse().unwrap();
+        let instr = instrument_index(toml_table(&config, "instr").unwrap().unwrap()).unwrap();
+        let channels = toml_table(&config, "channels").unwrap();
+        let mut warnings = Vec::new();
+        let renders = plan_renders(
+            &instr,
+            channels,
+            tracks,
+            &soundfont(),
+            &[],
+            false,
+            &mut |msg| warnings.push(msg),
+        );
+        (renders.into_iter().map(|r| r.0).collect(), warnings)
+    }
+
+    #[test]
+    fn notes_on_first_track() {
+        // A type 0 file, with the tempo and the notes on its only track
+        let mut events = vec![(0, tempo(400_000))];
+        events.extend(
+            melody(b"Piano", &[60, 64, 67])
+                .iter()
+                .map(|e| (e.delta.as_int(), e.kind)),
+        );
+        let (tracks, _) = sequence(vec![track(&events)]);
+        let config = "[[instr.Piano]]\npreset = 0";
+        assert_eq!(plan(&tracks, config), (vec!["Piano".to_string()], vec![]));
+
+        let sf = include_bytes!("../tests/data/sine.sf2");
+        let samples = crate::render_midi(&smf(vec![track(&events)]), sf, config).unwrap();
+        assert!(samples.iter().any(|s| s.abs() > 0.01));
+
+        // Notes on the first track of a type 1 file are no less in need of an instrument
+        let (tracks, _) = sequence(vec![melody(b"Lead", &[72]), melody(b"Piano", &[60])]);
+        assert_eq!(
+            plan(&tracks, config),
+            (
+                vec!["Piano".to_string()],
+                vec!["No instruments defined for Lead, skipping track!".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn empty_tracks_not_rendered() {
+        // A named and configured track, but without notes until its end a bar later
+        let empty = track(&[
+            (0, track_name(b"Strings")),
+            (
+                1920,
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            ),
+        ]);
+        let config = "[[instr.Piano]]\npreset = 0\n[[instr.Strings]]\npreset = 40";
+        let (tracks, _) = sequence(vec![empty.clone(), melody(b"Piano", &[60])]);
+        assert_eq!(tracks[0].length, 2_000_000);
+        assert_eq!(
+            plan(&tracks, config),
+            (
+                vec!["Piano".to_string()],
+                vec!["No notes in Strings, skipping track!".to_string()]
+            )
+        );
+
+        // With nothing else to render, nothing is mixed
+        let sf = include_bytes!("../tests/data/sine.sf2");
+        assert!(matches!(
+            crate::render_midi(&smf(vec![empty]), sf, config),
+            Err(crate::MidiSynthError::Render(_))
+        ));
+    }
+
+    #[test]
+    fn latin1_track_name() {
+        // "Flöte" in Latin-1, and in UTF-8 for comparison
+        let (tracks, _) = sequence(vec![
+            melody(b"Fl\xf6te", &[72]),
+            melody("Flöte".as_bytes(), &[60]),
+        ]);
+        assert_eq!(tracks[0].name.as_deref(), Some("Flöte"));
+        assert_eq!(tracks[1].name, tracks[0].name);
+        let (parts, _) = plan(&tracks, "[[instr.\"Flöte\"]]\npreset = 0");
+        assert_eq!(parts, ["Flöte", "Flöte"]);
+    }
+
+    #[test]
+    fn adversarial_tick_order() {
+        // Three tracks of irregular deltas, from none to millions of ticks, with tempo changes
+        // on two of them falling between, on and next to the notes of the others
+        let mut lcg = 12345_u32;
+        let mut delta = move || {
+            lcg = lcg.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            match lcg >> 28 {
+                0..=5 => 0,
+                6..=11 => (lcg >> 24) & 0xf,
+                12..=14 => (lcg >> 16) & 0x3ff,
+                _ => (lcg >> 8) & 0x3f_ffff,
+            }
+        };
+        let mut file = Vec::new();
+        let mut note_ticks = Vec::new();
+        for idx in 0..3 {
+            let (mut events, mut ticks, mut starts) = (Vec::new(), 0_u64, Vec::new());
+            for i in 0..300 {
+                let d = delta();
+                ticks += u64::from(d);
+                if idx > 0 && i % 3 == 0 {
+                    events.push((d, tempo(250_000 + i * 997)));
+                } else {
+                    starts.push(ticks);
+                    events.extend([(d, note_on(60, 100)), (0, note_off(60))]);
+                }
+            }
+            file.push(track(&events));
+            note_ticks.push(starts);
+        }
+
+        let (tracks, timemap) = sequence(file);
+        let end = timemap.time_at(timemap.end_ticks);
+        for (track, starts) in tracks.iter().zip(note_ticks) {
+            let times: Vec<_> = track.events.iter().map(|e| e.time).collect();
+            let expected: Vec<_> = starts.iter().map(|&t| timemap.time_at(t)).collect();
+            assert_eq!(times, expected);
+            assert!(times.is_sorted() && times.iter().all(|&t| t <= end));
+        }
+    }
+
+    #[test]
+    fn ticks_beyond_u32() {
+        // Notes the longest delta apart run past u32::MAX ticks by the 17th, and those of a
+        // second track fall just after the first's on either side of it
+        const LONGEST: u32 = 0x0fff_ffff;
+        let mut first = Vec::new();
+        for _ in 0..17 {
+            first.extend([(LONGEST, note_on(60, 100)), (0, note_off(60))]);
+        }
+        let mut second = vec![(LONGEST, note_off(0)); 16];
+        second.extend([(1, note_on(64, 100)), (0, note_off(64))]);
+        second.extend([(LONGEST, note_on(64, 100)), (0, note_off(64))]);
+        let (tracks, timemap) = sequence(vec![track(&first), track(&second)]);
+
+        let ticks = |n: u64| n * u64::from(LONGEST);
+        assert_eq!(timemap.end_ticks, ticks(17) + 1);
+        assert!(timemap.end_ticks > u64::from(u32::MAX));
+        let times = |t: &PlayerTrack| t.events.iter().map(|e| e.time).collect::<Vec<_>>();
+        let expected: Vec<_> = (1..=17).map(|n| timemap.time_at(ticks(n))).collect();
+        assert_eq!(times(&tracks[0]), expected);
+        let expected = [ticks(16) + 1, ticks(17) + 1].map(|t| timemap.time_at(t));
+        assert_eq!(times(&tracks[1]), expected);
+        let ideal = (ticks(17) + 1) as f64 * 500_000.0 / 480.0;
+        assert_eq!(expected[1], ideal.round() as usize);
+    }
+
+    #[test]
+    fn volume_and_expression_multiply() {
+        // A held note under a second of CC7 (127 to 40) and CC11 (127 to 80) ramps, then held
+        let cc = |controller: u8, value: u32| midly::TrackEventKind::Midi {
+            channel: 0.into(),
+            message: midly::MidiMessage::Controller {
+                controller: controller.into(),
+                value: (value as u8).into(),
+            },
+        };
+        let ramp = |volume: bool, expression: bool| {
+            let mut events = vec![(0, note_on(69, 100))];
+            for step in 0..=48_u32 {
+                let delta = if step == 0 { 0 } else { 20 };
+                if volume {
+                    events.push((delta, cc(7, 127 - step * 87 / 48)));
+                }
+                if expression {
+                    let delta = if volume { 0 } else { delta };
+                    events.push((delta, cc(11, 127 - step * 47 / 48)));
+                }
+            }
+            let rest = if volume || expression { 480 } else { 1440 };
+            events.push((rest, note_off(69)));
+            events.push((
+                0,
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            ));
+            let (tracks, _) = sequence(vec![track(&events)]);
+            let is = instrument("preset = 0\ncc_interp = \"linear\"").unwrap();
+            // Without the reverb, whose tail of the louder past doesn't follow the gain
+            let mut settings = RenderQuality::default().settings(44100, None);
+            settings.enable_reverb_and_chorus = false;
+            let mut renderer = renderer(tracks[0].clone(), 44100);
+            renderer.synth = rustysynth::Synthesizer::new(&soundfont(), &settings).unwrap();
+            renderer.render(&is, 0, None, &()).0
+        };
+        let rms = |samples: &[f32], secs: f32| {
+            let at = (secs * 44100.0) as usize;
+            let window = &samples[at..at + 4410];
+            (window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32).sqrt()
+        };
+
+        // Gains relative to the synthesizer's defaults, which a ramp of one leaves to the other
+        let (none, both) = (ramp(false, false), ramp(true, true));
+        let (volume, expression) = (ramp(true, false), ramp(false, true));
+        for secs in [0.4, 0.7, 1.2] {
+            let gain = |samples: &[f32]| rms(samples, secs) / rms(&none, secs);
+            let (v, e) = (gain(&volume), gain(&expression));
+            assert!(v < 0.9 && e < 0.9, "{} {}", v, e);
+            assert!(
+                (gain(&both) - v * e).abs() < 0.01,
+                "{} != {} * {}",
+                gain(&both),
+                v,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn sample_rates_align() {
+        // A three-quarter second track with a note, offset by 123.4 ms
+        let (tracks, _) = sequence(vec![track(&[
+            (0, note_on(69, 100)),
+            (480, note_off(69)),
+            (
+                240,
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            ),
+        ])]);
+        let is = instrument("preset = 0\noffset_ms = 123.4").unwrap();
+        let mix = |sr: usize| {
+            let (left, right) = renderer(tracks[0].clone(), sr).render(&is, 0, None, &());
+            let track = MixerTrack {
+                name: "Piano".into(),
+                track: None,
+                left,
+                right,
+                gainfactors: MixerGainFactors::from_setting(&is, &tracks[0]),
+                offset: is.offset(sr),
+                output: 0,
+                bus: None,
+            };
+            let mixer = Mixer {
+                tracks: vec![track],
+                precision: MixPrecision::Auto,
+                channels: 2,
+            };
+            let wavdata = mixer.mix(&());
+            let onset = wavdata.chunks(2).position(|f| f[0] != 0.0).unwrap();
+            (
+                onset as f64 / sr as f64,
+                wavdata.len() as f64 / 2.0 / sr as f64,
+            )
+        };
+
+        let sample = 1.0 / 44100.0;
+        let (onset, length) = mix(44100);
+        // The sine starts from zero, so its first sample past the offset is silent
+        assert!((onset - 0.1234 - sample).abs() <= sample, "{}", onset);
+        assert!((length - 0.8734).abs() <= sample, "{}", length);
+        let (onset_48k, length_48k) = mix(48000);
+        assert!(
+            (onset_48k - onset).abs() <= sample,
+            "{} {}",
+            onset_48k,
+            onset
+        );
+        assert!(
+            (length_48k - length).abs() <= sample,
+            "{} {}",
+            length_48k,
+            length
+        );
+    }
+
+    #[test]
+    fn conflicting_tempos_lowest_track_wins() {
+        // Two conductor tracks changing the tempo differently on the second beat
+        let conductors = |first: u32, second: u32| {
+            vec![
+                track(&[(480, tempo(first))]),
+                track(&[(480, tempo(second))]),
+                melody(b"Piano", &[60, 62, 64]),
+            ]
+        };
+        for (first, second) in [(600_000, 300_000), (300_000, 600_000)] {
+            let (tracks, timemap) = sequence(conductors(first, second));
+            let times: Vec<_> = tracks[2].events.iter().map(|e| e.time).collect();
+            assert_eq!(times, [0, 500_000, 500_000 + first as usize]);
+            assert_eq!(timemap.tempo_conflicts, 1);
+
+            let mut seq = Sequencer {
+                tracks: conductors(first, second)
+                    .into_iter()
+                    .map(SequencedTrack::create)
+                    .collect(),
+                timemap: TimeMap::default(),
+            };
+            assert_eq!(seq.tempo_changes(), [(480, first as usize)]);
+            let timing = midly::Timing::Metrical(480.into());
+            let tracks = seq.play_all(timing, true, &()).unwrap();
+            assert_eq!(tracks[2].events[2].tempo, first as usize);
+        }
+    }
+
+    #[test]
+    fn double_mix_of_many_tracks() {
+        // 64 tracks of noise at levels 40 dB apart, summed against an exact reference
+        let mut noise = 0x2545_f491_u32;
+        let mut next = move || {
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            noise as f32 / u32::MAX as f32 - 0.5
+        };
+        let tracks: Vec<_> = (0..64)
+            .map(|i| MixerTrack {
+                name: format!("Track {}", i),
+                track: None,
+                left: (0..4096).map(|_| next()).collect(),
+                right: (0..4096).map(|_| next()).collect(),
+                gainfactors: MixerGainFactors::new([0.0, -40.0][i % 2], 0.0),
+                offset: i as isize,
+                output: 0,
+                bus: None,
+            })
+            .collect();
+        let mut reference = vec![0_f64; 2 * (4096 + 63)];
+        for t in tracks.iter() {
+            let gf = &t.gainfactors;
+            let start = 2 * t.offset as usize;
+            for (i, (il, ir)) in t.left.iter().zip(t.right.iter()).enumerate() {
+                reference[start + 2 * i] += f64::from(gf.l_to_l * il + gf.r_to_l * ir);
+                reference[start + 2 * i + 1] += f64::from(gf.l_to_r * il + gf.r_to_r * ir);
+            }
+        }
+
+        let mut mixer = Mixer {
+            tracks,
+            precision: MixPrecision::Single,
+            channels: 2,
+        };
+        let error = |mix: &[f32]| -> Vec<f64> {
+            mix.iter()
+                .zip(&reference)
+                .map(|(&s, r)| (f64::from(s) - r).abs())
+                .collect()
+        };
+        let single = error(&mixer.mix(&()));
+        mixer.precision = MixPrecision::Auto;
+        let double = error(&mixer.mix(&()));
+
+        // Summed in double precision, only the final rounding to f32 remains
+        for (e, r) in double.iter().zip(&reference) {
+            assert!(
+                *e <= r.abs() * f64::from(f32::EPSILON) / 2.0,
+                "{} of {}",
+                e,
+                r
+            );
+        }
+        let total = |errors: &[f64]| errors.iter().sum::<f64>();
+        assert!(total(&single) > 4.0 * total(&double), "{}", total(&single));
+    }
+
+    #[test]
+    fn split_channels_long_gap() {
+        // Channel 2 plays a note every 2^27 ticks, past u32::MAX ticks, while channel 1 only
+        // plays at the start and at the end, more than the longest delta apart
+        const STEP: u32 = 1 << 27;
+        let on = |channel: u8, key: u8| midly::TrackEventKind::Midi {
+            channel: channel.into(),
+            message: midly::MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 100.into(),
+            },
+        };
+        let mut events = vec![(0, tempo(400_000)), (0, on(0, 60))];
+        for _ in 0..40 {
+            events.push((STEP, on(1, 64)));
+        }
+        events.push((0, on(0, 67)));
+        events.push((
+            STEP,
+            midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        ));
+        let single = midly::Smf {
+            header: midly::Header::new(
+                midly::Format::SingleTrack,
+                midly::Timing::Metrical(480.into()),
+            ),
+            tracks: vec![track(&events)],
+        };
+        let names: Vec<_> = (1..=16).map(|ch| format!("Channel {}", ch)).collect();
+        let split = split_channels(&single, &names).unwrap();
+        assert_eq!(split.header.format, midly::Format::Parallel);
+
+        // The absolute ticks of the events of each track, leaving out the bridging texts
+        fn ticks<'a>(track: &midly::Track<'a>) -> Vec<(u64, midly::TrackEventKind<'a>)> {
+            let mut ticks = 0_u64;
+            let mut events = Vec::new();
+            for e in track {
+                ticks += u64::from(e.delta.as_int());
+                match e.kind {
+                    midly::TrackEventKind::Meta(midly::MetaMessage::Text(b"")) => {}
+                    kind => events.push((ticks, kind)),
+                }
+            }
+            events
+        }
+        let step = |n: u64| n * u64::from(STEP);
+        let end = (
+            step(41),
+            midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        );
+        assert!(end.0 > u64::from(u32::MAX));
+        assert_eq!(split.tracks.len(), 3);
+        assert_eq!(ticks(&split.tracks[0]), [(0, tempo(400_000)), end]);
+        let name = |n| midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(n));
+        let first = [
+            (0, name(b"Channel 1")),
+            (0, on(0, 60)),
+            (step(40), on(0, 67)),
+            end,
+        ];
+        assert_eq!(ticks(&split.tracks[1]), first);
+        let mut second = vec![(0, name(b"Channel 2"))];
+        second.extend((1..=40).map(|n| (step(n), on(1, 64))));
+        second.push(end);
+        assert_eq!(ticks(&split.tracks[2]), second);
+    }
+
+    #[test]
+    fn scale_of_equal_temperament() {
+        // 12-TET in cents and as the 2/1 period plays every key at its own pitch
+        let mut scl = "! 12-tet.scl\n12-TET\n 12\n!\n".to_string();
+        for step in 1..12 {
+            scl += &format!(" {}.0\n", step * 100);
+        }
+        scl += " 2/1\n";
+        let scale = Scale::from_scl(&scl).unwrap();
+        assert_eq!(scale.pitches.len(), 12);
+        for key in 0..=127 {
+            let (nearest, bend) = scale.retune(key).unwrap();
+            assert_eq!(nearest, key);
+            assert!(bend.abs() < 1e-9, "{} {}", key, bend);
+        }
+    }
+
+    #[test]
+    fn scale_of_ratios_and_cents() {
+        // A just major second, a cent value and a bare 2 for the octave
+        let scale = Scale::from_scl("mix\n3\n9/8\n386.3137 a third\n2\n").unwrap();
+        let just = 1200.0 * (9.0_f64 / 8.0).log2();
+        assert_eq!(scale.pitches, [just, 386.3137, 1200.0]);
+        assert_eq!((scale.cents(1), scale.cents(3)), (just, 1200.0));
+        assert_eq!(scale.cents(-2), just - 1200.0);
+
+        // Mapped linearly, A above middle C is degree 9, three periods up, at 440 Hz
+        let (key, bend) = scale.retune(61).unwrap();
+        let pitch = 69.0 + (just - 3600.0) / 100.0;
+        assert_eq!(key, 35);
+        assert!((f64::from(key) + bend / 100.0 - pitch).abs() < 1e-9);
+        assert_eq!(scale.retune(69), Some((69, 0.0)));
+    }
+
+    #[test]
+    fn keyboard_map_with_unmapped_keys() {
+        // 7-EDO on the white keys, an octave of 12 keys being 7 degrees, from A0 to C8
+        let mut scl = "7-EDO\n7\n".to_string();
+        for step in 1..=7 {
+            scl += &format!("{:.6}\n", f64::from(step) * 1200.0 / 7.0);
+        }
+        let kbm = "! white.kbm\n12\n21\n108\n60\n69\n440.0\n7\n! C ..\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+        let mut white = Scale::from_scl(&scl).unwrap();
+        white.parse_kbm(kbm).unwrap();
+        assert_eq!(white.keys, (21, 108));
+        assert_eq!(white.octave_degree, 7);
+
+        let step = 1200.0 / 7.0;
+        let pitch = |key: u8| {
+            white
+                .retune(key)
+                .map(|(n, bend)| f64::from(n) + bend / 100.0)
+        };
+        let expected = |degrees: f64| 69.0 + (degrees - 5.0) * step / 100.0;
+        for (key, degree) in [
+            (60, 0),
+            (62, 1),
+            (69, 5),
+            (71, 6),
+            (72, 7),
+            (57, -2),
+            (21, -23),
+        ] {
+            let p = pitch(key).unwrap();
+            assert!(
+                (p - expected(f64::from(degree))).abs() < 1e-4,
+                "{} {}",
+                key,
+                p
+            );
+            assert_eq!(white.degree(key.into()), Some(degree));
+        }
+        assert_eq!(white.retune(69), Some((69, 0.0)));
+        for key in [61, 63, 66, 68, 70, 73, 20, 109] {
+            assert_eq!(white.retune(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn scale_errors() {
+        for (scl, error) in [
+            ("", "missing description"),
+            ("! only a comment\n", "missing description"),
+            ("desc\n", "missing number of pitches"),
+            ("desc\nmany\n", "line 2: invalid number of pitches many"),
+            ("! c\ndesc\n0\n", "line 3: invalid number of pitches 0"),
+            ("desc\n2\n100.0\n", "1 of 2 pitches given"),
+            ("desc\n2\n3/0\n2/1\n", "line 3: invalid pitch 3/0"),
+            (
+                "desc\n2\n100.0\n! c\nseven\n",
+                "line 5: invalid pitch seven",
+            ),
+            (
+                "desc\n1\n-100.0\n",
+                "the last pitch, the period, must be above 1/1",
+            ),
+        ] {
+            assert_eq!(Scale::from_scl(scl).unwrap_err(), error, "{:?}", scl);
+        }
+
+        let scale = || Scale::from_scl("12-TET\n1\n100.0\n").unwrap();
+        for (kbm, error) in [
+            ("", "missing map size"),
+            ("twelve\n", "line 1: invalid map size twelve"),
+            ("0\n0\n200\n", "line 3: invalid last key 200"),
+            ("! c\n0\n0\n127\n60\n", "missing reference key"),
+            (
+                "0\n0\n127\n60\n69\n-440\n",
+                "line 6: invalid reference frequency -440",
+            ),
+            (
+                "0\n0\n127\n60\n69\n440\n-1\n",
+                "line 7: invalid octave degree -1",
+            ),
+            ("2\n0\n127\n60\n69\n440\n1\n0\n", "missing key mapping"),
+            (
+                "2\n0\n127\n60\n69\n440\n1\n0\ny\n",
+                "line 9: invalid degree y",
+            ),
+            (
+                "0\n100\n27\n60\n69\n440\n0\n",
+                "the first key is above the last key",
+            ),
+            (
+                "2\n0\n127\n60\n69\n440\n1\n0\nx\n",
+                "reference key 69 is not mapped",
+            ),
+        ] {
+            assert_eq!(scale().parse_kbm(kbm).unwrap_err(), error, "{:?}", kbm);
+        }
+
+        // render_midi reads no files, so it can't follow them
+        let config = "tuning_file = \"19-edo.scl\"\n[[instr.Piano]]\npreset = 0\n";
+        let midi = smf(vec![melody(b"Piano", &[60])]);
+        let result = crate::render_midi(&midi, include_bytes!("../tests/data/sine.sf2"), config);
+        assert!(matches!(result, Err(crate::MidiSynthError::Config(_))));
+    }
+
+    #[test]
+    fn strum_chords_not_runs() {
+        let event = |time: usize, note: u8, duration: usize| PlayerEvent {
+            time,
+            note,
+            velocity: 100,
+            channel: 0,
+            tempo: 500_000,
+            duration,
+            release_velocity: 64,
+        };
+        // A chord given out of pitch order, then a descending run of notes 15ms apart, each
+        // within the chord window of the previous one but only every other one of the first
+        let mut events = vec![
+            event(0, 67, 400_000),
+            event(0, 60, 400_000),
+            event(5_000, 64, 400_000),
+        ];
+        events.extend((0..4).map(|i| event(500_000 + i * 15_000, 72 - i as u8, 15_000)));
+        let summary = |events: &[PlayerEvent]| {
+            events
+                .iter()
+                .map(|e| (e.time, e.note, e.time + e.duration))
+                .collect::<Vec<_>>()
+        };
+
+        // Each chord is spread from its own onset, keeping the end of each note
+        let up = [
+            (0, 60, 400_000),
+            (10_000, 64, 405_000),
+            (20_000, 67, 400_000),
+            (510_000, 72, 515_000),
+            (515_000, 71, 530_000),
+            (540_000, 70, 545_000),
+            (545_000, 69, 560_000),
+        ];
+        assert_eq!(summary(&strum(&events, 10_000, false)), up);
+
+        // Strummed down, the pairs of the run are already in order
+        let mut down = summary(&events[3..]);
+        down.splice(
+            0..0,
+            [
+                (0, 67, 400_000),
+                (10_000, 64, 405_000),
+                (20_000, 60, 400_000),
+            ],
+        );
+        assert_eq!(summary(&strum(&events, 10_000, true)), down);
+    }
+
+    #[test]
+    fn tempo_sections_override_bars() {
+        // Eight bars of 4/4 at 120 BPM, a note on every downbeat, with the file changing the
+        // tempo within bar 4. The first track ends early, before the changes to be added.
+        let meter = |num: u8, denom: u8| {
+            midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(num, denom, 24, 8))
+        };
+        let end = midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack);
+        let conductor = track(&[(0, tempo(500_000)), (0, meter(4, 2)), (100, end)]);
+        let mut notes = Vec::new();
+        for bar in 0..8 {
+            notes.extend([
+                (bar * 1920, note_on(60, 100)),
+                (bar * 1920 + 480, note_off(60)),
+            ]);
+        }
+        // After the note of bar 4 as the file is, still in bar 4 as overridden
+        notes.extend([(6480, tempo(400_000)), (7200, note_on(62, 100))]);
+        notes.extend([(7200, note_off(62)), (15360, end)]);
+        notes.sort_by_key(|e| e.0);
+        let mut previous = 0;
+        for e in notes.iter_mut() {
+            (e.0, previous) = (e.0 - previous, e.0);
+        }
+        let mut tracks = vec![conductor, track(&notes)];
+
+        // Bars 3 and 4 in 3/4 at 60 BPM, then the file's tempo and meter from bar 5 on
+        let sections = [
+            TempoSection {
+                bar: 3,
+                tempo: Some(1_000_000),
+                meter: Some((3, 2)),
+            },
+            TempoSection {
+                bar: 5,
+                tempo: None,
+                meter: None,
+            },
+        ];
+        apply_tempo_sections(&mut tracks, &sections, 480);
+        let (tracks, timemap) = sequence(tracks);
+
+        // Bar 3 starts at tick 3840, bar 5 two bars of 3/4 later at 6720, handing back the
+        // tempo the file changed to in between
+        assert_eq!(
+            timemap.tempos[1..],
+            [
+                (0, 0.0, 500_000),
+                (3840, 4e6, 1_000_000),
+                (6720, 10e6, 400_000)
+            ]
+        );
+        assert_eq!(timemap.meters, [(0, 4, 2), (3840, 3, 2), (6720, 4, 2)]);
+        let bars: Vec<_> = (1..=6).map(|bar| timemap.bar_start(bar).unwrap()).collect();
+        assert_eq!(
+            bars,
+            [0, 2_000_000, 4_000_000, 7_000_000, 10_000_000, 11_600_000]
+        );
+
+        // The notes keep their ticks, the first track now ends after the added changes
+        let starts: Vec<_> = tracks[1].events.iter().map(|e| e.time).collect();
+        let ticks = [0, 1920, 3840, 5760, 7200, 7680, 9600, 11520, 13440];
+        assert_eq!(starts, ticks.map(|t| timemap.time_at(t)));
+        assert_eq!(tracks[0].length, timemap.time_at(6720));
+    }
+
+    #[test]
+    fn xfade_layers() {
+        // The louder layer of a pair is found among other layers, other pairings are errors
+        let layers = |toml: &str| -> Vec<toml::Value> {
+            let config: toml::Table = toml.parse().unwrap();
+            config["instr"]["Piano"].as_array().unwrap().clone()
+        };
+        let pair = |toml: &str| xfade_pair(layers(toml).iter());
+        let pp = "[[instr.Piano]]\npreset = 0\nxfade_lo = 20\nxfade_hi = 100\n";
+        let ff = "[[instr.Piano]]\npreset = 1\nxfade_lo = 20\nxfade_hi = 100\n";
+        let other = "[[instr.Piano]]\npreset = 2\n";
+        assert_eq!(pair(other), Ok(None));
+        assert_eq!(pair(&[other, pp, other, ff].concat()), Ok(Some(3)));
+        let error = Err("xfade_lo and xfade_hi must be set alike on a pair of layers".into());
+        assert_eq!(pair(pp), error);
+        assert_eq!(pair(&[pp, pp, ff].concat()), error);
+        assert_eq!(pair(&[pp, &ff.replace("100", "90")].concat()), error);
+        assert_eq!(
+            pair(&[pp, "[[instr.Piano]]\npreset = 1\nxfade_lo = 20\n"].concat()),
+            error
+        );
+
+        // Below the crossfade only the first layer sounds, above it only the second, and halfway
+        // through both at equal power
+        let rms = |(lo, hi): (u8, u8), velocity: u8, up: bool| {
+            let (tracks, _) = sequence(vec![track(&[
+                (0, note_on(69, velocity)),
+                (960, note_off(69)),
+                (
+                    0,
+                    midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+                ),
+            ])]);
+            let toml = format!("preset = 0\nxfade_lo = {}\nxfade_hi = {}", lo, hi);
+            let mut is = instrument(&toml).unwrap();
+            is.xfade_up = up;
+            let (left, _) = renderer(tracks[0].clone(), 44100).render(&is, 0, None, &());
+            (left.iter().map(|s| s * s).sum::<f32>() / left.len() as f32).sqrt()
+        };
+        assert_eq!(rms((61, 100), 60, true), 0.0);
+        assert_eq!(rms((20, 59), 60, false), 0.0);
+        let full = rms((61, 100), 60, false);
+        assert!(full > 0.001 && full == rms((20, 59), 60, true));
+        for up in [false, true] {
+            let half = rms((40, 80), 60, up) / full;
+            assert!(
+                (half - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.001,
+                "{}",
+                half
+            );
+        }
+    }
+}